@@ -1,34 +1,312 @@
 #[cfg(test)]
 use quickcheck::{Arbitrary, Gen};
 
-use serde::{Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::iter::Sum;
 use std::num::ParseIntError;
+use std::ops::{Add, Sub};
 use std::str::FromStr;
 
 // to avoid floating point arithmetics we represent amounts as int
-// number of «minimal representable amount»s — 0.0001
+// number of «minimal representable amount»s — 10^-DECIMALS. For `Amount`
+// that is 0.0001, so the largest amount is 1844674407370955.1615 and the
+// largest whole one 1844674407370955
 type Money = u64;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct Amount(Money);
+/// A non-negative amount with `DECIMALS` decimal places, at most 19.
+/// Transactions use `Amount`; others are for currencies with different
+/// minor units, e.g. `ScaledAmount<0>` for JPY or `ScaledAmount<8>` for
+/// bitcoin.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct ScaledAmount<const DECIMALS: u32>(Money);
+
+pub type Amount = ScaledAmount<4>;
+
+impl<const DECIMALS: u32> ScaledAmount<DECIMALS> {
+    // how many minor units make a whole one
+    const SCALE: Money = 10_u64.pow(DECIMALS);
+
+    pub const ZERO: Self = Self(0);
 
-impl Amount {
     pub fn new() -> Self {
-        Self(0)
+        Self::ZERO
+    }
+
+    pub fn is_zero(self) -> bool {
+        self == Self::ZERO
+    }
+
+    /// An amount of `units` × 10^-`DECIMALS`, the smallest representable
+    /// amount: for `Amount` one minor unit is 0.0001, so 10000 of them make
+    /// 1. Exact, for systems storing integer cents or micros.
+    pub fn from_minor_units(units: Money) -> Self {
+        Self(units)
+    }
+
+    /// The inverse of `from_minor_units`, losing no precision.
+    pub fn to_minor_units(self) -> Money {
+        self.0
     }
 
     pub fn checked_add(self, v: Self) -> Option<Self> {
-        let Amount(x) = self;
-        let Amount(y) = v;
-        x.checked_add(y).map(Amount)
+        let Self(x) = self;
+        let Self(y) = v;
+        x.checked_add(y).map(Self)
     }
 
     pub fn checked_sub(self, v: Self) -> Option<Self> {
-        let Amount(x) = self;
-        let Amount(y) = v;
-        x.checked_sub(y).map(Amount)
+        let Self(x) = self;
+        let Self(y) = v;
+        x.checked_sub(y).map(Self)
+    }
+
+    /// Adds, clamping at the largest amount instead of overflowing.
+    pub fn saturating_add(self, v: Self) -> Self {
+        Self(self.0.saturating_add(v.0))
+    }
+
+    /// Subtracts, clamping at zero instead of going negative.
+    pub fn saturating_sub(self, v: Self) -> Self {
+        Self(self.0.saturating_sub(v.0))
+    }
+
+    /// Returns the magnitude of `self - v` along with how `self` compares to
+    /// `v`. The magnitude always fits, `Option` just keeps it in line with
+    /// the other checked operations.
+    pub fn checked_sub_signed(self, v: Self) -> Option<(Ordering, Self)> {
+        let Self(x) = self;
+        let Self(y) = v;
+        let ord = x.cmp(&y);
+        match ord {
+            Ordering::Less => y.checked_sub(x),
+            _ => x.checked_sub(y),
+        }
+        .map(|diff| (ord, Self(diff)))
+    }
+
+    /// `Ord::clamp` without having to import `Ord`. Panics if `min > max`.
+    pub fn clamp(self, min: Self, max: Self) -> Self {
+        Ord::clamp(self, min, max)
+    }
+
+    /// Parses like `from_str`, but only minimal forms: `"1.5"` and not
+    /// `"1.50"` or `"1.5000"`.
+    pub fn parse_strict(s: &str) -> Result<Self, ParseAmountError> {
+        match s.trim_matches(' ').split_once('.') {
+            Some((_, fps)) if fps.ends_with('0') => {
+                Err(ParseAmountError::NotMinimal)
+            }
+            _ => s.parse(),
+        }
+    }
+
+    /// Parses `"numerator/denominator"`, rounding the quotient to `DECIMALS`
+    /// decimal places. Anything without a `/` is parsed as a plain amount.
+    pub fn parse_fraction(
+        s: &str,
+        rounding: Rounding,
+    ) -> Result<Self, ParseAmountError> {
+        let (nums, dens) = match s.split_once('/') {
+            Some(parts) => parts,
+            None => return s.parse(),
+        };
+        let num: Money = nums.parse()?;
+        let den: Money = dens.parse()?;
+        if den == 0 {
+            return Err(ParseAmountError::ZeroDenominator);
+        }
+        let scale = u128::from(Self::SCALE);
+        let x = rounding.div(u128::from(num) * scale, u128::from(den));
+        Money::try_from(x)
+            .map(Self)
+            .map_err(|_| ParseAmountError::TooLarge)
+    }
+
+    /// Formats with exactly `decimals` decimal places, rounding away the
+    /// extra precision. Unlike `Display` trailing zeros are kept.
+    pub fn display_rounded(
+        self,
+        decimals: usize,
+        rounding: Rounding,
+    ) -> String {
+        let Self(x) = self;
+        let d = decimals.min(DECIMALS as usize);
+        let units =
+            rounding.div(u128::from(x), 10_u128.pow(DECIMALS - d as u32));
+        if decimals == 0 {
+            return format!("{}", units);
+        }
+        let scale = 10_u128.pow(d as u32);
+        // with no decimal places of its own all of them are padding
+        let fraction = match d {
+            0 => String::new(),
+            _ => format!("{:0width$}", units % scale, width = d),
+        };
+        format!("{}.{}{}", units / scale, fraction, "0".repeat(decimals - d))
+    }
+}
+
+/// A balance that can drop below zero, e.g. when funds already withdrawn
+/// get disputed. Its magnitude stays within what `ScaledAmount` can hold.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SignedScaledAmount<const DECIMALS: u32>(i128);
+
+pub type SignedAmount = SignedScaledAmount<4>;
+
+impl<const DECIMALS: u32> SignedScaledAmount<DECIMALS> {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn checked_add(self, v: ScaledAmount<DECIMALS>) -> Option<Self> {
+        let Self(x) = self;
+        let ScaledAmount(y) = v;
+        Self::checked(x + i128::from(y))
+    }
+
+    pub fn checked_sub(self, v: ScaledAmount<DECIMALS>) -> Option<Self> {
+        let Self(x) = self;
+        let ScaledAmount(y) = v;
+        Self::checked(x - i128::from(y))
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// A balance of `units` × 10^-`DECIMALS`, or `None` if its magnitude is
+    /// more than `ScaledAmount` can hold.
+    pub fn from_minor_units(units: i128) -> Option<Self> {
+        Self::checked(units)
+    }
+
+    /// The inverse of `from_minor_units`.
+    pub fn to_minor_units(self) -> i128 {
+        self.0
+    }
+
+    /// `ScaledAmount::display_rounded` with a sign, which is left out when
+    /// the rounded value is zero.
+    pub fn display_rounded(
+        self,
+        decimals: usize,
+        rounding: Rounding,
+    ) -> String {
+        let magnitude = self.magnitude().display_rounded(decimals, rounding);
+        if self.is_negative()
+            && magnitude.bytes().any(|b| b != b'0' && b != b'.')
+        {
+            format!("-{}", magnitude)
+        } else {
+            magnitude
+        }
+    }
+
+    /// The balance as a `ScaledAmount`, unless it is negative.
+    pub fn to_amount(self) -> Option<ScaledAmount<DECIMALS>> {
+        Money::try_from(self.0).ok().map(ScaledAmount)
+    }
+
+    fn checked(x: i128) -> Option<Self> {
+        if x.unsigned_abs() <= u128::from(Money::MAX) {
+            Some(Self(x))
+        } else {
+            None
+        }
+    }
+
+    fn magnitude(self) -> ScaledAmount<DECIMALS> {
+        ScaledAmount(self.0.unsigned_abs() as Money)
+    }
+}
+
+impl<const DECIMALS: u32> From<ScaledAmount<DECIMALS>>
+    for SignedScaledAmount<DECIMALS>
+{
+    fn from(amount: ScaledAmount<DECIMALS>) -> Self {
+        let ScaledAmount(x) = amount;
+        Self(i128::from(x))
+    }
+}
+
+impl<const DECIMALS: u32> PartialEq<ScaledAmount<DECIMALS>>
+    for SignedScaledAmount<DECIMALS>
+{
+    fn eq(&self, other: &ScaledAmount<DECIMALS>) -> bool {
+        *self == Self::from(*other)
+    }
+}
+
+impl<const DECIMALS: u32> FromStr for SignedScaledAmount<DECIMALS> {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_matches(' ').strip_prefix('-') {
+            Some(magnitude) => {
+                let ScaledAmount::<DECIMALS>(x) = magnitude.parse()?;
+                Ok(Self(-i128::from(x)))
+            }
+            None => s.parse::<ScaledAmount<DECIMALS>>().map(Self::from),
+        }
+    }
+}
+
+impl<const DECIMALS: u32> Display for SignedScaledAmount<DECIMALS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_negative() {
+            write!(f, "-")?
+        }
+        write!(f, "{}", self.magnitude())
+    }
+}
+
+impl<const DECIMALS: u32> Serialize for SignedScaledAmount<DECIMALS> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{}", self))
+    }
+}
+
+impl<'de, const DECIMALS: u32> Deserialize<'de>
+    for SignedScaledAmount<DECIMALS>
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rounding {
+    Floor,
+    HalfUp,
+    HalfEven,
+}
+
+impl Rounding {
+    fn div(self, n: u128, d: u128) -> u128 {
+        let (q, r) = (n / d, n % d);
+        let round_up = match self {
+            Self::Floor => false,
+            Self::HalfUp => 2 * r >= d,
+            Self::HalfEven => 2 * r > d || (2 * r == d && q % 2 == 1),
+        };
+        if round_up {
+            q + 1
+        } else {
+            q
+        }
     }
 }
 
@@ -37,7 +315,34 @@ pub enum ParseAmountError {
     Parse(ParseIntError),
     TooLarge,
     MultipleDots,
-    TooPrecise,
+    // more decimal places than the amount has
+    TooPrecise(u32),
+    ZeroDenominator,
+    // e.g. a newline from a quoted field spanning lines
+    InvalidCharacter(char),
+    NotMinimal,
+    // e.g. an empty amount field of a deposit
+    Missing,
+    // a `,` that doesn't separate groups of three digits
+    BadGrouping,
+}
+
+impl ParseAmountError {
+    /// A stable name of the error for machine-readable reports, e.g.
+    /// `too_precise`.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Self::Parse(_) => "invalid_number",
+            Self::TooLarge => "amount_too_large",
+            Self::MultipleDots => "multiple_dots",
+            Self::TooPrecise(_) => "too_precise",
+            Self::ZeroDenominator => "zero_denominator",
+            Self::InvalidCharacter(_) => "invalid_character",
+            Self::NotMinimal => "not_minimal",
+            Self::Missing => "missing_amount",
+            Self::BadGrouping => "bad_grouping",
+        }
+    }
 }
 
 impl From<ParseIntError> for ParseAmountError {
@@ -52,7 +357,16 @@ impl Display for ParseAmountError {
             Self::Parse(ref perr) => write!(f, "int parsing error: {}", perr),
             Self::TooLarge => write!(f, "number is too large"),
             Self::MultipleDots => write!(f, "wrong format: multiple dots"),
-            Self::TooPrecise => write!(f, "unsupported precision of >4"),
+            Self::TooPrecise(decimals) => {
+                write!(f, "unsupported precision of >{}", decimals)
+            }
+            Self::ZeroDenominator => write!(f, "zero denominator"),
+            Self::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            Self::NotMinimal => {
+                write!(f, "not in minimal form: trailing fractional zeros")
+            }
+            Self::Missing => write!(f, "missing amount"),
+            Self::BadGrouping => write!(f, "misplaced thousands separator"),
         }
     }
 }
@@ -66,32 +380,60 @@ impl Error for ParseAmountError {
     }
 }
 
-impl FromStr for Amount {
+// `ips` without the `,` separating thousands, which must group all of its
+// digits by three, the leftmost group having one to three of them
+fn ungroup(ips: &str) -> Result<Cow<'_, str>, ParseAmountError> {
+    if !ips.contains(',') {
+        return Ok(Cow::Borrowed(ips));
+    }
+    let mut groups = ips.split(',');
+    let first = groups.next().unwrap_or("");
+    if first.is_empty() || first.len() > 3 || groups.any(|g| g.len() != 3) {
+        return Err(ParseAmountError::BadGrouping);
+    }
+    Ok(Cow::Owned(ips.replace(',', "")))
+}
+
+/// Parses a decimal like `1234.5`. Spaces around it are ignored, and the
+/// integer part may have its thousands separated by `,` as in `1,234.5`;
+/// other commas fail with `BadGrouping`.
+impl<const DECIMALS: u32> FromStr for ScaledAmount<DECIMALS> {
     type Err = ParseAmountError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(c) = s.chars().find(|c| c.is_control()) {
+            return Err(Self::Err::InvalidCharacter(c));
+        }
+        let s = s.trim_matches(' ');
         match s.split('.').collect::<Vec<&str>>().as_slice() {
             [ips] => {
-                let x: Money = ips.parse()?;
-                Ok(Amount(x * 10000))
+                let x: Money = ungroup(ips)?.parse()?;
+                x.checked_mul(Self::SCALE)
+                    .map(Self)
+                    .ok_or(Self::Err::TooLarge)
             }
             [ips, fps] => {
-                let ip: Money = if ips.is_empty() { 0 } else { ips.parse()? };
+                let ip: Money = if ips.is_empty() {
+                    0
+                } else {
+                    ungroup(ips)?.parse()?
+                };
 
+                if fps.contains(',') {
+                    return Err(Self::Err::BadGrouping);
+                }
                 let fps = fps.trim_end_matches('0');
-                if fps.len() > 4 {
-                    return Err(Self::Err::TooPrecise);
+                if fps.len() > DECIMALS as usize {
+                    return Err(Self::Err::TooPrecise(DECIMALS));
                 }
                 let mut fp: Money =
                     if fps.is_empty() { 0 } else { fps.parse()? };
-                if fps.len() < 4 {
-                    let pad = 4 - fps.len();
-                    fp *= 10_u64.pow(pad as u32);
-                }
+                let pad = DECIMALS - fps.len() as u32;
+                fp *= 10_u64.pow(pad);
 
-                match ip.checked_mul(10000) {
+                match ip.checked_mul(Self::SCALE) {
                     Some(x) => match x.checked_add(fp) {
-                        Some(res) => Ok(Amount(res)),
+                        Some(res) => Ok(Self(res)),
                         None => Err(Self::Err::TooLarge),
                     },
                     None => Err(Self::Err::TooLarge),
@@ -102,24 +444,59 @@ impl FromStr for Amount {
     }
 }
 
-impl Display for Amount {
+/// `a + b` panics on overflow, even in release builds; use `checked_add` or
+/// `saturating_add` for amounts that aren't known to fit.
+impl<const DECIMALS: u32> Add for ScaledAmount<DECIMALS> {
+    type Output = Self;
+
+    fn add(self, v: Self) -> Self {
+        self.checked_add(v).expect("amount overflow")
+    }
+}
+
+/// `a - b` panics if `b > a`, even in release builds; use `checked_sub` or
+/// `saturating_sub` otherwise.
+impl<const DECIMALS: u32> Sub for ScaledAmount<DECIMALS> {
+    type Output = Self;
+
+    fn sub(self, v: Self) -> Self {
+        self.checked_sub(v).expect("negative amount")
+    }
+}
+
+/// Sums like `+`, panicking once the total doesn't fit: for `Amount` past
+/// 1844674407370955.1615, `u64::MAX` minor units. Fold with `checked_add`
+/// to handle that instead.
+impl<const DECIMALS: u32> Sum for ScaledAmount<DECIMALS> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+impl<'a, const DECIMALS: u32> Sum<&'a Self> for ScaledAmount<DECIMALS> {
+    fn sum<I: Iterator<Item = &'a Self>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+impl<const DECIMALS: u32> Display for ScaledAmount<DECIMALS> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let Self(x) = self;
-        let mut fp: Money = x % 10000;
+        let mut fp: Money = x % Self::SCALE;
         if fp > 0 {
-            let mut width = 4;
+            let mut width = DECIMALS as usize;
             while fp % 10 == 0 {
                 fp /= 10;
                 width -= 1
             }
-            write!(f, "{}.{:0width$}", x / 10000, fp, width = width)
+            write!(f, "{}.{:0width$}", x / Self::SCALE, fp, width = width)
         } else {
-            write!(f, "{}", x / 10000)
+            write!(f, "{}", x / Self::SCALE)
         }
     }
 }
 
-impl Serialize for Amount {
+impl<const DECIMALS: u32> Serialize for ScaledAmount<DECIMALS> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -128,14 +505,90 @@ impl Serialize for Amount {
     }
 }
 
+/// Reads amounts written as strings like `"1.5"`, as `Serialize` writes
+/// them; see `minor_units` for integers.
+impl<'de, const DECIMALS: u32> Deserialize<'de> for ScaledAmount<DECIMALS> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+/// For `#[serde(deserialize_with = "minor_units::deserialize")]` on fields
+/// holding an integer count of 0.0001s instead of a decimal string.
+pub mod minor_units {
+    use super::*;
+
+    pub fn deserialize<'de, D, const DECIMALS: u32>(
+        deserializer: D,
+    ) -> Result<ScaledAmount<DECIMALS>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_u64(AmountVisitor)
+    }
+}
+
+struct AmountVisitor<const DECIMALS: u32>;
+
+impl<'de, const DECIMALS: u32> Visitor<'de> for AmountVisitor<DECIMALS> {
+    type Value = ScaledAmount<DECIMALS>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount or an integer number of minor units")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        s.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, units: u64) -> Result<Self::Value, E> {
+        Ok(ScaledAmount::from_minor_units(units))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_deserialize() {
+        use serde::de::value::Error;
+        use serde::de::IntoDeserializer;
+
+        let from_str = |s: &str| {
+            Amount::deserialize(IntoDeserializer::<Error>::into_deserializer(s))
+        };
+        assert_eq!(from_str("1.5"), Ok(Amount::from_minor_units(15000)));
+        assert!(from_str("1.23456").is_err());
+        let from_u64 = |x: u64| {
+            minor_units::deserialize(
+                IntoDeserializer::<Error>::into_deserializer(x),
+            )
+        };
+        assert_eq!(from_u64(15000), Ok(Amount::from_minor_units(15000)));
+
+        #[derive(Deserialize)]
+        struct Row {
+            amount: Amount,
+            #[serde(deserialize_with = "minor_units::deserialize")]
+            minor: Amount,
+        }
+        let data = "amount,minor\n1.5,20000\n";
+        let rows: Vec<Row> = csv::Reader::from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].amount, Amount::from_minor_units(15000));
+        assert_eq!(rows[0].minor, Amount::from_minor_units(20000));
+    }
+
     #[test]
     fn test_deser() {
         fn d(s: &str) -> u64 {
-            let Amount(x) = s.parse().unwrap();
+            let ScaledAmount(x): Amount = s.parse().unwrap();
             x
         }
         assert_eq!(d("1"), 10000);
@@ -146,7 +599,8 @@ mod tests {
         assert_eq!(d(".060"), 600);
         assert_eq!(d("010.0010"), 100010);
         assert_eq!(d("100000000000"), 100_000_000_000_0000);
-        assert_eq!(d("+1."), 10000)
+        assert_eq!(d("+1."), 10000);
+        assert_eq!(d("1844674407370955"), 18_446_744_073_709_550_000)
     }
 
     #[test]
@@ -165,14 +619,75 @@ mod tests {
         assert!("-1".parse::<Amount>().is_err());
         assert!("-0".parse::<Amount>().is_err());
         assert_eq!(d("1844674407370955.1616"), E::TooLarge);
+        assert_eq!(d("1844674407370956"), E::TooLarge);
+        assert_eq!(d("18446744073709551615"), E::TooLarge);
         assert_eq!(d(".12."), E::MultipleDots);
-        assert_eq!(d(".01234"), E::TooPrecise);
+        assert_eq!(d(".01234"), E::TooPrecise(4));
+        assert_eq!(d("1.5\n0"), E::InvalidCharacter('\n'));
+        assert_eq!(d("\t1"), E::InvalidCharacter('\t'));
+        assert_eq!(d("1\r\n").to_string(), "invalid character '\\r'");
+    }
+
+    #[test]
+    fn test_padding_and_grouping() {
+        let a = |s: &str| s.parse::<Amount>();
+        let ok = |s: &str| Amount::from_minor_units(s.parse().unwrap());
+        assert_eq!(a(" 1,234.5 "), Ok(ok("12345000")));
+        assert_eq!(a("1,234,567"), Ok(ok("12345670000")));
+        assert_eq!(a("123,456.0001"), Ok(ok("1234560001")));
+        assert_eq!(a("  7 "), Ok(ok("70000")));
+        assert_eq!(a("1234.5"), Ok(ok("12345000")));
+        assert_eq!(a("0.5"), Ok(ok("5000")));
+        assert_eq!(
+            " -1,000".parse::<SignedAmount>().unwrap().to_string(),
+            "-1000"
+        );
+
+        for bad in &["1,2,3", "1,23", "1234,567", ",123", "1,", "1.234,5"] {
+            assert_eq!(a(bad), Err(ParseAmountError::BadGrouping), "{}", bad);
+        }
+        assert_eq!(a("1,234.5.6"), Err(ParseAmountError::MultipleDots));
+        assert_eq!(a(" 1.23456 "), Err(ParseAmountError::TooPrecise(4)));
+        assert_eq!(a("1,844,674,407,370,956"), Err(ParseAmountError::TooLarge));
+        assert!(a("1 234").is_err());
+        assert!(a(" ").is_err());
+        assert_eq!(
+            Amount::parse_strict(" 1.50 "),
+            Err(ParseAmountError::NotMinimal)
+        );
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        assert_eq!(
+            Amount::parse_strict("1.5"),
+            Ok(Amount::from_minor_units(15000))
+        );
+        assert_eq!(
+            Amount::parse_strict("10"),
+            Ok(Amount::from_minor_units(100000))
+        );
+        assert_eq!(
+            Amount::parse_strict("1.50"),
+            Err(ParseAmountError::NotMinimal)
+        );
+        assert_eq!(
+            Amount::parse_strict("1.5000"),
+            Err(ParseAmountError::NotMinimal)
+        );
+        assert_eq!(
+            Amount::parse_strict("1.0"),
+            Err(ParseAmountError::NotMinimal)
+        );
+        assert!(Amount::parse_strict("1.5.0").is_err());
+        // the default stays lenient
+        assert_eq!("1.50".parse(), Ok(Amount::from_minor_units(15000)));
     }
 
     #[test]
     fn test_ser() {
         fn s(x: u64) -> String {
-            format!("{}", Amount(x))
+            format!("{}", Amount::from_minor_units(x))
         }
         assert_eq!(s(0), "0");
         assert_eq!(s(12300), "1.23");
@@ -181,9 +696,225 @@ mod tests {
         assert_eq!(s(100_000_000_000_0000), "100000000000")
     }
 
-    impl Arbitrary for Amount {
-        fn arbitrary(g: &mut Gen) -> Amount {
-            Amount(Money::arbitrary(g))
+    #[test]
+    fn test_checked_sub_signed() {
+        fn d(a: &str, b: &str) -> (Ordering, String) {
+            let a: Amount = a.parse().unwrap();
+            let (ord, diff) = a.checked_sub_signed(b.parse().unwrap()).unwrap();
+            (ord, format!("{}", diff))
+        }
+        assert_eq!(d("10", "2.5"), (Ordering::Greater, "7.5".to_string()));
+        assert_eq!(d("2.5", "10"), (Ordering::Less, "7.5".to_string()));
+        assert_eq!(d("1.0001", "1.0001"), (Ordering::Equal, "0".to_string()));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let a = |s: &str| s.parse::<Amount>().unwrap();
+        assert!(a("0.1") < a("0.1001"));
+        assert!(a("1") > a("0.9999"));
+        assert!(a("2") >= a("2.0000"));
+        let mut amounts = vec![a("10"), a("0.5"), a("2"), a(".05"), a("0")];
+        amounts.sort();
+        assert_eq!(amounts, vec![a("0"), a(".05"), a("0.5"), a("2"), a("10")]);
+        assert_eq!(amounts.iter().max(), Some(&a("10")));
+
+        assert!(Amount::ZERO.is_zero());
+        assert!(a("0.0000").is_zero());
+        assert!(!a("0.0001").is_zero());
+        assert_eq!(Amount::new(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let (min, max) = (
+            Amount::from_minor_units(10000),
+            Amount::from_minor_units(50000),
+        );
+        assert_eq!(Amount::from_minor_units(1).clamp(min, max), min);
+        assert_eq!(Amount::from_minor_units(50001).clamp(min, max), max);
+        assert_eq!(
+            Amount::from_minor_units(20000).clamp(min, max),
+            Amount::from_minor_units(20000)
+        );
+        assert_eq!(min.clamp(min, min), min);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_clamp_min_above_max() {
+        Amount::from_minor_units(20000).clamp(
+            Amount::from_minor_units(50000),
+            Amount::from_minor_units(10000),
+        );
+    }
+
+    #[test]
+    fn test_parse_fraction() {
+        fn d(s: &str, rounding: Rounding) -> Result<u64, ParseAmountError> {
+            Amount::parse_fraction(s, rounding).map(|ScaledAmount(x)| x)
+        }
+        assert_eq!(d("1/3", Rounding::HalfUp), Ok(3333));
+        assert_eq!(d("2/3", Rounding::HalfUp), Ok(6667));
+        assert_eq!(d("2/3", Rounding::Floor), Ok(6666));
+        assert_eq!(d("10/4", Rounding::Floor), Ok(25000));
+        assert_eq!(d("1/20000", Rounding::HalfEven), Ok(0));
+        assert_eq!(d("3/20000", Rounding::HalfEven), Ok(2));
+        assert_eq!(d("1.5", Rounding::Floor), Ok(15000));
+        assert_eq!(
+            d("1/0", Rounding::HalfUp),
+            Err(ParseAmountError::ZeroDenominator)
+        );
+        assert_eq!(
+            d("18446744073709551615/1", Rounding::Floor),
+            Err(ParseAmountError::TooLarge)
+        );
+        assert!(d("1/x", Rounding::Floor).is_err());
+        assert!(d("1/2/3", Rounding::Floor).is_err());
+    }
+
+    #[test]
+    fn test_display_rounded() {
+        fn r(s: &str, decimals: usize, rounding: Rounding) -> String {
+            s.parse::<Amount>()
+                .unwrap()
+                .display_rounded(decimals, rounding)
+        }
+        assert_eq!(r("1.2345", 4, Rounding::Floor), "1.2345");
+        assert_eq!(r("1.2", 4, Rounding::Floor), "1.2000");
+        assert_eq!(r("1.2", 6, Rounding::Floor), "1.200000");
+        assert_eq!(r("1.2399", 2, Rounding::Floor), "1.23");
+        assert_eq!(r("1.2399", 2, Rounding::HalfEven), "1.24");
+        assert_eq!(r("1.235", 2, Rounding::HalfEven), "1.24");
+        assert_eq!(r("1.225", 2, Rounding::HalfEven), "1.22");
+        assert_eq!(r("1.2251", 2, Rounding::HalfEven), "1.23");
+        assert_eq!(r("0.9999", 2, Rounding::Floor), "0.99");
+        assert_eq!(r("0.9999", 2, Rounding::HalfEven), "1.00");
+        assert_eq!(r("9.5", 0, Rounding::HalfEven), "10");
+        let signed = |s: &str, decimals| {
+            s.parse::<SignedAmount>()
+                .unwrap()
+                .display_rounded(decimals, Rounding::HalfEven)
+        };
+        assert_eq!(signed("-1.5", 4), "-1.5000");
+        assert_eq!(signed("-0.0001", 2), "0.00");
+        assert_eq!(signed("-0.009", 2), "-0.01");
+        assert_eq!(signed("2", 0), "2");
+        assert_eq!(r("8.5", 0, Rounding::HalfEven), "8");
+        assert_eq!(r("8.5", 0, Rounding::Floor), "8");
+    }
+
+    #[test]
+    fn test_scales() {
+        type Yen = ScaledAmount<0>;
+        type Btc = ScaledAmount<8>;
+
+        let yen: Yen = "1500".parse().unwrap();
+        assert_eq!(yen, Yen::from_minor_units(1500));
+        assert_eq!(yen.to_string(), "1500");
+        assert_eq!("1500.".parse(), Ok(yen));
+        assert_eq!("1500.00".parse(), Ok(yen));
+        assert_eq!("1.5".parse::<Yen>(), Err(ParseAmountError::TooPrecise(0)));
+        assert_eq!(
+            Yen::parse_fraction("7/2", Rounding::HalfEven),
+            Ok(Yen::from_minor_units(4))
+        );
+        assert_eq!(yen.display_rounded(2, Rounding::HalfEven), "1500.00");
+        assert_eq!(yen.display_rounded(0, Rounding::HalfEven), "1500");
+
+        let sat: Btc = "0.00000001".parse().unwrap();
+        assert_eq!(sat, Btc::from_minor_units(1));
+        assert_eq!(sat.to_string(), "0.00000001");
+        let btc: Btc = "21.5".parse().unwrap();
+        assert_eq!(btc, Btc::from_minor_units(2_150_000_000));
+        assert_eq!(btc.to_string(), "21.5");
+        assert_eq!(
+            "0.000000001".parse::<Btc>().unwrap_err().to_string(),
+            "unsupported precision of >8"
+        );
+        assert_eq!(sat.display_rounded(2, Rounding::HalfUp), "0.00");
+        assert_eq!(btc.display_rounded(10, Rounding::Floor), "21.5000000000");
+        let almost: Btc = "0.99999999".parse().unwrap();
+        assert_eq!(almost.display_rounded(4, Rounding::HalfEven), "1.0000");
+        assert_eq!(almost.display_rounded(4, Rounding::Floor), "0.9999");
+        // less room for the whole part
+        assert_eq!(
+            "184467440738".parse::<Btc>(),
+            Err(ParseAmountError::TooLarge)
+        );
+
+        let debt = SignedScaledAmount::from(sat).checked_sub(btc).unwrap();
+        assert_eq!(debt.to_string(), "-21.49999999");
+        assert_eq!("-21.49999999".parse(), Ok(debt));
+    }
+
+    #[test]
+    fn test_signed_amount() {
+        let a = |s: &str| s.parse::<Amount>().unwrap();
+        let x = SignedAmount::from(a("1.5")).checked_sub(a("4")).unwrap();
+        assert!(x.is_negative());
+        assert_eq!(x.to_string(), "-2.5");
+        assert_eq!(x.to_amount(), None);
+        assert_eq!("-2.5".parse(), Ok(x));
+        assert_eq!(x.checked_add(a("2.5")), Some(SignedAmount::new()));
+        assert_eq!(SignedAmount::new().to_string(), "0");
+        assert_eq!("-0".parse(), Ok(SignedAmount::new()));
+        assert_eq!(x.checked_add(a("3")).unwrap(), a("0.5"));
+        assert!("--1".parse::<SignedAmount>().is_err());
+
+        let max = SignedAmount::from(Amount::from_minor_units(Money::MAX));
+        assert_eq!(max.checked_add(a("0.0001")), None);
+        let min = SignedAmount::new()
+            .checked_sub(Amount::from_minor_units(Money::MAX))
+            .unwrap();
+        assert_eq!(min.to_string(), "-1844674407370955.1615");
+        assert_eq!(min.checked_sub(a("0.0001")), None);
+    }
+
+    #[test]
+    fn test_operators() {
+        let a = |s: &str| s.parse::<Amount>().unwrap();
+        assert_eq!(a("1.5") + a("0.25"), a("1.75"));
+        assert_eq!(a("1.5") - a("0.25"), a("1.25"));
+        let max = Amount::from_minor_units(Money::MAX);
+        assert_eq!(max.saturating_add(a("1")), max);
+        assert_eq!(a("1").saturating_sub(a("2")), Amount::ZERO);
+        assert!(std::panic::catch_unwind(|| max + a("0.0001")).is_err());
+        assert!(std::panic::catch_unwind(|| a("1") - a("2")).is_err());
+    }
+
+    #[test]
+    fn test_minor_units() {
+        assert_eq!(Amount::from_minor_units(10000).to_string(), "1");
+        assert_eq!(Amount::from_minor_units(1).to_string(), "0.0001");
+        assert_eq!("2.5".parse::<Amount>().unwrap().to_minor_units(), 25000);
+        for units in [0, 1, 9999, 10000, 12345, Money::MAX] {
+            let amount = Amount::from_minor_units(units);
+            assert_eq!(amount.to_minor_units(), units);
+            assert_eq!(amount.to_string().parse(), Ok(amount));
+        }
+    }
+
+    #[test]
+    fn test_sum() {
+        let a = |s: &str| s.parse::<Amount>().unwrap();
+        let deposits = [a("1.5"), a("0.0001"), a("20"), a("0")];
+        assert_eq!(deposits.iter().copied().sum::<Amount>(), a("21.5001"));
+        assert_eq!(deposits.iter().sum::<Amount>(), a("21.5001"));
+        assert_eq!(Vec::<Amount>::new().into_iter().sum::<Amount>(), a("0"));
+
+        let max = Amount::from_minor_units(Money::MAX);
+        assert_eq!([max, Amount::ZERO].iter().sum::<Amount>(), max);
+        let overflowing = [max, a("0.0001")];
+        assert!(std::panic::catch_unwind(|| overflowing
+            .iter()
+            .sum::<Amount>())
+        .is_err());
+    }
+
+    impl<const DECIMALS: u32> Arbitrary for ScaledAmount<DECIMALS> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Self(Money::arbitrary(g))
         }
     }
 
@@ -196,5 +927,21 @@ mod tests {
                 false
             }
         }
+
+        fn prop_minor_units_round_trip(amount: Amount) -> bool {
+            Amount::from_minor_units(amount.to_minor_units()) == amount
+        }
+
+        fn prop_saturating_add(a: Amount, b: Amount) -> bool {
+            let sum = a.saturating_add(b);
+            sum >= a && sum >= b && a.saturating_sub(b) <= a
+        }
+
+        fn prop_add_is_checked_add(a: Amount, b: Amount) -> bool {
+            match a.checked_add(b) {
+                Some(sum) => a + b == sum && sum - b == a,
+                None => a.saturating_add(b).to_minor_units() == Money::MAX,
+            }
+        }
     }
 }
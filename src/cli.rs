@@ -0,0 +1,437 @@
+// read when no input path is given on the command line, before falling
+// back to stdin
+pub const INPUT_ENV: &str = "PAYMENTS_INPUT";
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Command {
+    // print client summaries
+    #[default]
+    Process,
+    // only report rejected rows and counts, exiting with an error if there
+    // are any; also `process --check`
+    Validate,
+    // compare two summaries, exiting with an error if they differ
+    Diff,
+    // write random transactions, e.g. for benchmarks
+    Gen,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Format {
+    #[default]
+    Csv,
+    // newline-delimited JSON objects
+    Json,
+}
+
+#[derive(Default)]
+pub struct Options {
+    pub command: Command,
+    // `None` means stdin
+    pub input_path: Option<String>,
+    // of the transactions read; summaries are always CSV
+    pub format: Format,
+    // the `type` column holds numeric kind codes, 1 for deposits etc.
+    pub numeric_kinds: bool,
+    // stop reading once retained transactions take roughly this many
+    // bytes, still printing the summary so far
+    pub max_memory: Option<usize>,
+    // print an estimate of the memory taken by the processor's state to
+    // stderr after processing
+    pub mem_report: bool,
+    // add deposit and withdrawal counts and active disputes to summaries
+    pub verbose: bool,
+    // after the summary, a second CSV section of the rejected rows' tx and
+    // client ids with the error
+    pub include_rejections: bool,
+    // print summary amounts with exactly this many decimal places instead
+    // of trimming trailing zeros
+    pub decimals: Option<usize>,
+    // for `diff`, the new summary compared against the one at `input_path`
+    pub diff_path: Option<String>,
+    // print the outcome of every transaction as a JSON line instead of the
+    // final summary
+    pub emit_results: bool,
+    // where to copy rows that failed to parse or process, with the error
+    pub dead_letter_path: Option<String>,
+    // where to write a CSV of the rejected rows' line, tx id, a stable
+    // error kind and the message
+    pub errors_out_path: Option<String>,
+    // skip a transaction identical to the one right before it
+    pub dedup_consecutive: bool,
+    // CSV of client balances before this batch; also adds opening and
+    // closing columns to the summary
+    pub opening_balances_path: Option<String>,
+    // also print the summary after every this many rows, before the final
+    // one
+    pub checkpoint_every: Option<usize>,
+    // where to also write the summaries of clients with a negative balance
+    pub alert_negative_path: Option<String>,
+    // seed client map hashing, to reproduce its iteration order when
+    // debugging; for `gen`, seeds the transactions
+    pub seed: Option<u64>,
+    // keep waiting for rows appended to the input file instead of stopping
+    // at its end; summaries then only come from checkpoints
+    pub follow: bool,
+    // how often to look for new rows when following, 1s by default
+    pub poll_interval_ms: Option<u64>,
+    // sort the final summary through temporary files, for more clients than
+    // fit in memory twice
+    pub external_sort: bool,
+    // how many summaries `--external-sort` sorts in memory at once
+    pub sort_chunk_len: Option<usize>,
+    // process on this many threads, each with its share of the clients;
+    // only the summary is printed
+    pub parallel: Option<usize>,
+    // reject amounts with trailing fractional zeros, like `1.50`
+    pub strict_amounts: bool,
+    // let disputes of already withdrawn funds make available negative
+    pub allow_negative: bool,
+    // print how long processing took and the rows per second to stderr
+    pub timing: bool,
+    // for `gen`, how many clients and transactions to generate
+    pub gen_clients: Option<u16>,
+    pub gen_txs: Option<usize>,
+    // for `gen`, where to write the transactions; `None` means stdout
+    pub output_path: Option<String>,
+}
+
+impl Options {
+    /// Accepts `[process|validate] [options] [path]`; a bare path means
+    /// `process`. Without a path, `PAYMENTS_INPUT` is used if set, and
+    /// stdin otherwise. `diff <old> <new>` takes two paths instead, and
+    /// `gen --clients N --txs M [--output path]` none.
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.peekable();
+        let mut opts = Self::default();
+        match args.peek().map(String::as_str) {
+            Some("process") => {
+                args.next();
+            }
+            Some("validate") => {
+                args.next();
+                opts.command = Command::Validate
+            }
+            Some("diff") => {
+                args.next();
+                opts.command = Command::Diff
+            }
+            Some("gen") => {
+                args.next();
+                opts.command = Command::Gen
+            }
+            _ => (),
+        }
+
+        let mut input_path = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--emit-results" => opts.emit_results = true,
+                "--dead-letter" => {
+                    opts.dead_letter_path =
+                        Some(args.next().ok_or("--dead-letter needs a path")?)
+                }
+                "--errors-out" => {
+                    opts.errors_out_path =
+                        Some(args.next().ok_or("--errors-out needs a path")?)
+                }
+                "--dedup-consecutive" => opts.dedup_consecutive = true,
+                "--opening-balances" => {
+                    opts.opening_balances_path = Some(
+                        args.next().ok_or("--opening-balances needs a path")?,
+                    )
+                }
+                "--alert-negative" => {
+                    opts.alert_negative_path = Some(
+                        args.next().ok_or("--alert-negative needs a path")?,
+                    )
+                }
+                "--follow" => opts.follow = true,
+                "--external-sort" => opts.external_sort = true,
+                "--validate-amounts-strict" => opts.strict_amounts = true,
+                "--allow-negative" => opts.allow_negative = true,
+                "--timing" => opts.timing = true,
+                "--numeric-kinds" => opts.numeric_kinds = true,
+                "--mem-report" => opts.mem_report = true,
+                "--verbose" => opts.verbose = true,
+                "--include-rejections" => opts.include_rejections = true,
+                "--decimals" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n <= 19)
+                        .ok_or("--decimals needs a number up to 19")?;
+                    opts.decimals = Some(n)
+                }
+                "--poll-interval" => {
+                    let ms = args.next().and_then(|ms| ms.parse().ok());
+                    opts.poll_interval_ms =
+                        Some(ms.ok_or("--poll-interval needs milliseconds")?)
+                }
+                "--clients" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or("--clients needs a positive number")?;
+                    opts.gen_clients = Some(n)
+                }
+                "--txs" => {
+                    let n = args.next().and_then(|n| n.parse().ok());
+                    opts.gen_txs = Some(n.ok_or("--txs needs a number")?)
+                }
+                "--format" => {
+                    opts.format = match args.next().as_deref() {
+                        Some("csv") => Format::Csv,
+                        Some("json") => Format::Json,
+                        _ => return Err("--format needs csv or json".into()),
+                    }
+                }
+                "--output" => {
+                    opts.output_path =
+                        Some(args.next().ok_or("--output needs a path")?)
+                }
+                "--max-memory" => {
+                    let bytes = args.next().and_then(|n| n.parse().ok());
+                    opts.max_memory =
+                        Some(bytes.ok_or("--max-memory needs bytes")?)
+                }
+                "--seed" => {
+                    let seed = args.next().and_then(|n| n.parse().ok());
+                    opts.seed = Some(seed.ok_or("--seed needs a number")?)
+                }
+                "--check" if opts.command == Command::Process => {
+                    opts.command = Command::Validate
+                }
+                "--sort-chunk" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or("--sort-chunk needs a positive number")?;
+                    opts.sort_chunk_len = Some(n)
+                }
+                "--parallel" => {
+                    let n = args.next().and_then(|n| n.parse().ok());
+                    let n = n
+                        .filter(|n| *n > 0)
+                        .ok_or("--parallel needs a positive number")?;
+                    opts.parallel = Some(n)
+                }
+                "--checkpoint-every" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or("--checkpoint-every needs a positive number")?;
+                    opts.checkpoint_every = Some(n)
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option {}", flag))
+                }
+                _ if opts.command == Command::Gen => {
+                    return Err(format!("unexpected argument {}", arg))
+                }
+                _ if input_path.is_none() => input_path = Some(arg),
+                _ if opts.command == Command::Diff
+                    && opts.diff_path.is_none() =>
+                {
+                    opts.diff_path = Some(arg)
+                }
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        if opts.command == Command::Diff && opts.diff_path.is_none() {
+            return Err("diff needs two summaries".to_string());
+        }
+        if opts.command == Command::Gen
+            && (opts.gen_clients.is_none() || opts.gen_txs.is_none())
+        {
+            return Err("gen needs --clients and --txs".to_string());
+        }
+        opts.input_path = input_path.or_else(|| {
+            std::env::var(INPUT_ENV)
+                .ok()
+                .filter(|path| !path.is_empty())
+        });
+        if opts.follow && opts.input_path.is_none() {
+            return Err("--follow needs an input path".to_string());
+        }
+        // the rows are processed away from the reader, and not all of them
+        if opts.parallel.is_some()
+            && (opts.command != Command::Process
+                || opts.emit_results
+                || opts.dead_letter_path.is_some()
+                || opts.errors_out_path.is_some()
+                || opts.include_rejections
+                || opts.dedup_consecutive
+                || opts.opening_balances_path.is_some()
+                || opts.checkpoint_every.is_some()
+                || opts.alert_negative_path.is_some()
+                || opts.max_memory.is_some()
+                || opts.mem_report
+                || opts.external_sort
+                || opts.follow)
+        {
+            return Err("--parallel only prints the summary".to_string());
+        }
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let opts = parse(&["txs.csv"]).unwrap();
+        assert_eq!(opts.command, Command::Process);
+        assert_eq!(opts.input_path.as_deref(), Some("txs.csv"));
+        assert!(!opts.emit_results);
+
+        let opts = parse(&["process", "txs.csv"]).unwrap();
+        assert_eq!(opts.command, Command::Process);
+        assert_eq!(opts.input_path.as_deref(), Some("txs.csv"));
+
+        let opts =
+            parse(&["validate", "--dedup-consecutive", "a.csv"]).unwrap();
+        assert_eq!(opts.command, Command::Validate);
+        assert_eq!(opts.input_path.as_deref(), Some("a.csv"));
+        assert!(opts.dedup_consecutive);
+
+        let opts = parse(&["--emit-results", "txs.csv"]).unwrap();
+        assert!(opts.emit_results);
+
+        let opts = parse(&["txs.csv", "--dead-letter", "rej.csv"]).unwrap();
+        assert_eq!(opts.input_path.as_deref(), Some("txs.csv"));
+        assert_eq!(opts.dead_letter_path, Some("rej.csv".to_string()));
+        assert!(parse(&["txs.csv", "--dead-letter"]).is_err());
+        let opts = parse(&["--errors-out", "err.csv", "txs.csv"]).unwrap();
+        assert_eq!(opts.errors_out_path.as_deref(), Some("err.csv"));
+        assert!(parse(&["txs.csv", "--errors-out"]).is_err());
+
+        assert!(
+            parse(&["--dedup-consecutive", "a.csv"])
+                .unwrap()
+                .dedup_consecutive
+        );
+
+        let opts = parse(&["--opening-balances", "o.csv", "a.csv"]).unwrap();
+        assert_eq!(opts.opening_balances_path, Some("o.csv".to_string()));
+
+        let opts = parse(&["--checkpoint-every", "100", "a.csv"]).unwrap();
+        assert_eq!(opts.checkpoint_every, Some(100));
+        assert!(parse(&["--checkpoint-every", "0", "a.csv"]).is_err());
+        assert!(parse(&["--checkpoint-every", "a.csv"]).is_err());
+
+        let opts = parse(&["a.csv", "--alert-negative", "neg.csv"]).unwrap();
+        assert_eq!(opts.alert_negative_path.as_deref(), Some("neg.csv"));
+        assert!(parse(&["a.csv", "--alert-negative"]).is_err());
+
+        assert_eq!(parse(&["--seed", "7", "a.csv"]).unwrap().seed, Some(7));
+        assert!(parse(&["--external-sort", "a.csv"]).unwrap().external_sort);
+        let opts = parse(&["--sort-chunk", "10", "a.csv"]).unwrap();
+        assert_eq!(opts.sort_chunk_len, Some(10));
+        assert!(parse(&["--sort-chunk", "0", "a.csv"]).is_err());
+        let opts = parse(&["--parallel", "4", "a.csv"]).unwrap();
+        assert_eq!(opts.parallel, Some(4));
+        assert!(parse(&["--parallel", "0", "a.csv"]).is_err());
+        assert!(parse(&["validate", "--parallel", "4", "a.csv"]).is_err());
+        assert!(parse(&["--parallel", "4", "--emit-results", "a"]).is_err());
+        let opts = parse(&["--validate-amounts-strict", "a.csv"]).unwrap();
+        assert!(opts.strict_amounts);
+        assert!(
+            parse(&["--allow-negative", "a.csv"])
+                .unwrap()
+                .allow_negative
+        );
+        assert!(parse(&["a.csv", "--timing"]).unwrap().timing);
+        assert_eq!(parse(&["a.csv"]).unwrap().format, Format::Csv);
+        let opts = parse(&["--format", "json", "a.json"]).unwrap();
+        assert_eq!(opts.format, Format::Json);
+        assert!(parse(&["--format", "xml", "a.xml"]).is_err());
+        assert!(parse(&["--numeric-kinds", "a.csv"]).unwrap().numeric_kinds);
+        let opts = parse(&["--max-memory", "1000000", "a.csv"]).unwrap();
+        assert_eq!(opts.max_memory, Some(1_000_000));
+        assert!(parse(&["--max-memory", "1G", "a.csv"]).is_err());
+        assert!(parse(&["--mem-report", "a.csv"]).unwrap().mem_report);
+        assert!(parse(&["a.csv", "--verbose"]).unwrap().verbose);
+        let opts = parse(&["a.csv", "--check"]).unwrap();
+        assert_eq!(opts.command, Command::Validate);
+        assert!(
+            parse(&["gen", "--clients", "1", "--txs", "1", "--check"]).is_err()
+        );
+        assert!(
+            parse(&["--include-rejections", "a.csv"])
+                .unwrap()
+                .include_rejections
+        );
+        let opts = parse(&["--decimals", "4", "a.csv"]).unwrap();
+        assert_eq!(opts.decimals, Some(4));
+        assert!(parse(&["--decimals", "20", "a.csv"]).is_err());
+        assert!(parse(&["--seed", "a.csv"]).is_err());
+
+        let opts =
+            parse(&["--follow", "--poll-interval", "100", "a.csv"]).unwrap();
+        assert!(opts.follow);
+        assert_eq!(opts.poll_interval_ms, Some(100));
+        assert!(parse(&["a.csv", "--poll-interval", "x"]).is_err());
+
+        assert!(parse(&["a.csv", "b.csv"]).is_err());
+        let opts = parse(&["diff", "a.csv", "b.csv"]).unwrap();
+        assert_eq!(opts.command, Command::Diff);
+        assert_eq!(opts.input_path.as_deref(), Some("a.csv"));
+        assert_eq!(opts.diff_path.as_deref(), Some("b.csv"));
+        assert!(parse(&["diff", "a.csv"]).is_err());
+        assert!(parse(&["diff", "a.csv", "b.csv", "c.csv"]).is_err());
+        assert!(parse(&["--nope", "a.csv"]).is_err());
+
+        let opts = parse(&["gen", "--clients", "10", "--txs", "1000"]).unwrap();
+        assert_eq!(opts.command, Command::Gen);
+        assert_eq!((opts.gen_clients, opts.gen_txs), (Some(10), Some(1000)));
+        assert_eq!(opts.output_path, None);
+        let opts = parse(&[
+            "gen",
+            "--clients",
+            "1",
+            "--txs",
+            "0",
+            "--output",
+            "t.csv",
+        ])
+        .unwrap();
+        assert_eq!(opts.output_path.as_deref(), Some("t.csv"));
+        assert!(parse(&["gen", "--clients", "10"]).is_err());
+        assert!(parse(&["gen", "--clients", "0", "--txs", "1"]).is_err());
+        assert!(parse(&["gen", "--clients", "1", "--txs", "1", "a"]).is_err());
+    }
+
+    // the only test touching INPUT_ENV, so it can't race with another
+    #[test]
+    fn test_input_env() {
+        std::env::remove_var(INPUT_ENV);
+        assert_eq!(parse(&[]).unwrap().input_path, None);
+        assert_eq!(parse(&["validate"]).unwrap().input_path, None);
+        assert!(parse(&["--follow"]).is_err());
+
+        std::env::set_var(INPUT_ENV, "env.csv");
+        let opts = parse(&["validate"]).unwrap();
+        assert_eq!(opts.command, Command::Validate);
+        assert_eq!(opts.input_path.as_deref(), Some("env.csv"));
+        assert_eq!(parse(&[]).unwrap().input_path.as_deref(), Some("env.csv"));
+        let opts = parse(&["arg.csv"]).unwrap();
+        assert_eq!(opts.input_path.as_deref(), Some("arg.csv"));
+        let opts = parse(&["--follow"]).unwrap();
+        assert_eq!(opts.input_path.as_deref(), Some("env.csv"));
+
+        std::env::set_var(INPUT_ENV, "");
+        assert_eq!(parse(&[]).unwrap().input_path, None);
+        std::env::remove_var(INPUT_ENV);
+    }
+}
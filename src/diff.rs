@@ -0,0 +1,343 @@
+use serde::de;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::amount::{Amount, Rounding, SignedAmount};
+use crate::process::ClientSummary;
+use crate::tx::ClientID;
+
+#[derive(Deserialize)]
+struct SummaryRow {
+    client: ClientID,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    // only in statements
+    #[serde(default)]
+    opening: Option<String>,
+    #[serde(default)]
+    closing: Option<String>,
+    // only in verbose summaries
+    #[serde(default)]
+    deposit_count: Option<u64>,
+    #[serde(default)]
+    withdrawal_count: Option<u64>,
+    #[serde(default)]
+    active_disputes: Option<u64>,
+}
+
+impl SummaryRow {
+    fn into_summary(self) -> Result<ClientSummary, String> {
+        let client = self.client;
+        let bad = |err| format!("bad amount of client {}: {}", client, err);
+        let amount = |s: &str| s.parse::<Amount>().map_err(bad);
+        let signed = |s: &str| s.parse::<SignedAmount>().map_err(bad);
+        let total = signed(&self.total)?;
+        Ok(ClientSummary {
+            id: client,
+            available: signed(&self.available)?,
+            held: amount(&self.held)?,
+            total,
+            locked: self.locked,
+            opening: match self.opening {
+                Some(ref s) => amount(s)?,
+                None => Amount::new(),
+            },
+            closing: match self.closing {
+                Some(ref s) => signed(s)?,
+                None => total,
+            },
+            disputes: 0,
+            deposit_count: self.deposit_count.unwrap_or(0),
+            withdrawal_count: self.withdrawal_count.unwrap_or(0),
+            active_disputes: self.active_disputes.unwrap_or(0),
+        })
+    }
+}
+
+/// A row as `write_summaries` writes it, with or without the statement and
+/// verbose columns. `disputes` isn't written, so it reads as 0.
+impl<'de> Deserialize<'de> for ClientSummary {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let row: SummaryRow = Deserialize::deserialize(deserializer)?;
+        row.into_summary().map_err(de::Error::custom)
+    }
+}
+
+/// Reads summaries as written by `process`, with or without the statement
+/// and verbose columns.
+pub fn read_summaries<R: io::Read>(
+    input: R,
+) -> Result<Vec<ClientSummary>, csv::Error> {
+    csv::Reader::from_reader(input).deserialize().collect()
+}
+
+/// Which columns summaries are written with besides the balances, and how
+/// their amounts are formatted.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Columns {
+    // opening and closing balances
+    pub statement: bool,
+    // deposit and withdrawal counts and active disputes
+    pub verbose: bool,
+    // amounts with exactly this many decimal places, rounded half to even,
+    // instead of trimming trailing zeros like `1` for `1.0000`
+    pub decimals: Option<usize>,
+}
+
+impl Columns {
+    fn amount(&self, amount: SignedAmount) -> String {
+        match self.decimals {
+            Some(decimals) => {
+                amount.display_rounded(decimals, Rounding::HalfEven)
+            }
+            None => amount.to_string(),
+        }
+    }
+}
+
+/// Writes summaries as `process` prints them, with the extra `columns`.
+pub fn write_summaries<W: io::Write>(
+    output: W,
+    summaries: impl Iterator<Item = Result<ClientSummary, csv::Error>>,
+    columns: Columns,
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(output);
+    let mut headers = vec!["client", "available", "held", "total", "locked"];
+    if columns.statement {
+        headers.extend(&["opening", "closing"]);
+    }
+    if columns.verbose {
+        headers.extend(&[
+            "deposit_count",
+            "withdrawal_count",
+            "active_disputes",
+        ]);
+    }
+    wtr.write_record(&headers)?;
+    for summary in summaries {
+        let s = summary?;
+        let mut record = vec![
+            s.id.to_string(),
+            columns.amount(s.available),
+            columns.amount(s.held.into()),
+            columns.amount(s.total),
+            s.locked.to_string(),
+        ];
+        if columns.statement {
+            record.extend(vec![
+                columns.amount(s.opening.into()),
+                columns.amount(s.closing),
+            ]);
+        }
+        if columns.verbose {
+            record.extend(vec![
+                s.deposit_count.to_string(),
+                s.withdrawal_count.to_string(),
+                s.active_disputes.to_string(),
+            ]);
+        }
+        wtr.write_record(&record)?
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Compares two summary CSVs, writing the old (`-`) and new (`+`) rows of
+/// every client whose balances or lock differ, or who is only in one of
+/// them. Returns how many clients differ.
+pub fn diff<R1: io::Read, R2: io::Read, W: io::Write>(
+    old: R1,
+    new: R2,
+    mut output: W,
+) -> Result<usize, csv::Error> {
+    let mut clients: BTreeMap<ClientID, (Option<_>, Option<_>)> =
+        BTreeMap::new();
+    for summary in read_summaries(old)? {
+        let id = summary.id;
+        clients.entry(id).or_default().0 = Some(summary);
+    }
+    for summary in read_summaries(new)? {
+        let id = summary.id;
+        clients.entry(id).or_default().1 = Some(summary);
+    }
+
+    let mut differing = 0;
+    for (old, new) in clients.values() {
+        if let (Some(old), Some(new)) = (old, new) {
+            if same_balances(old, new) {
+                continue;
+            }
+        }
+        differing += 1;
+        if let Some(old) = old {
+            writeln!(output, "-{}", row(old))?
+        }
+        if let Some(new) = new {
+            writeln!(output, "+{}", row(new))?
+        }
+    }
+    output.flush()?;
+    Ok(differing)
+}
+
+// opening and closing are left out: they are only there in statements
+fn same_balances(a: &ClientSummary, b: &ClientSummary) -> bool {
+    (a.available, a.held, a.total, a.locked)
+        == (b.available, b.held, b.total, b.locked)
+}
+
+fn row(s: &ClientSummary) -> String {
+    format!(
+        "{},{},{},{},{}",
+        s.id, s.available, s.held, s.total, s.locked
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    quickcheck! {
+        fn prop_summaries_round_trip(summaries: Vec<ClientSummary>) -> bool {
+            let mut output = Vec::new();
+            let rows = summaries.iter().cloned().map(Ok);
+            let columns = Columns {
+                statement: true,
+                verbose: true,
+                decimals: None,
+            };
+            write_summaries(&mut output, rows, columns).unwrap();
+            read_summaries(&output[..]).unwrap() == summaries
+        }
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let summaries = [
+            ClientSummary {
+                id: 1.into(),
+                available: "-1.5".parse().unwrap(),
+                held: "2".parse().unwrap(),
+                total: "0.5".parse().unwrap(),
+                locked: true,
+                opening: Amount::new(),
+                closing: "0.5".parse().unwrap(),
+                disputes: 0,
+                deposit_count: 0,
+                withdrawal_count: 0,
+                active_disputes: 0,
+            },
+            ClientSummary {
+                id: 2.into(),
+                available: "3".parse().unwrap(),
+                held: Amount::new(),
+                total: "3".parse().unwrap(),
+                locked: false,
+                opening: Amount::new(),
+                closing: "3".parse().unwrap(),
+                disputes: 0,
+                deposit_count: 0,
+                withdrawal_count: 0,
+                active_disputes: 0,
+            },
+        ];
+        let mut output = Vec::new();
+        let rows = summaries.iter().cloned().map(Ok);
+        write_summaries(&mut output, rows, Columns::default()).unwrap();
+        let read: Vec<ClientSummary> = csv::Reader::from_reader(&output[..])
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, summaries);
+
+        let bad = "client,available,held,total,locked\n1,1,-1,0,false\n";
+        let err = read_summaries(bad.as_bytes()).unwrap_err();
+        assert!(
+            err.to_string().contains("bad amount of client 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_decimals() {
+        let summary = ClientSummary {
+            id: 1.into(),
+            available: "1".parse().unwrap(),
+            held: "0.25".parse().unwrap(),
+            total: "1.25".parse().unwrap(),
+            locked: false,
+            opening: Amount::new(),
+            closing: "1.25".parse().unwrap(),
+            disputes: 0,
+            deposit_count: 0,
+            withdrawal_count: 0,
+            active_disputes: 0,
+        };
+        let write = |columns| {
+            let mut output = Vec::new();
+            let rows = std::iter::once(Ok(summary.clone()));
+            write_summaries(&mut output, rows, columns).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let header = "client,available,held,total,locked\n";
+        assert_eq!(
+            write(Columns::default()),
+            format!("{}1,1,0.25,1.25,false\n", header)
+        );
+        let fixed = write(Columns {
+            decimals: Some(4),
+            ..Columns::default()
+        });
+        assert_eq!(fixed, format!("{}1,1.0000,0.2500,1.2500,false\n", header));
+        assert_eq!(
+            read_summaries(fixed.as_bytes()).unwrap(),
+            std::slice::from_ref(&summary)
+        );
+        let statement = write(Columns {
+            statement: true,
+            decimals: Some(1),
+            ..Columns::default()
+        });
+        assert!(statement.ends_with("\n1,1.0,0.2,1.2,false,0.0,1.2\n"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = "client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,1,3,false
+4,0,0,0,true
+";
+        let new = "client,available,held,total,locked,opening,closing
+1,1.5,0,1.5,false,1,1.5
+2,2,0,2,true,0,2
+3,7,0,7,false,0,7
+4,0,0,0,true,0,0
+";
+        let mut output = Vec::new();
+        let differing =
+            diff(old.as_bytes(), new.as_bytes(), &mut output).unwrap();
+        assert_eq!(differing, 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "-2,2,1,3,false\n+2,2,0,2,true\n+3,7,0,7,false\n"
+        );
+
+        let bad = "client,available,held,total,locked\n1,x,0,0,false\n";
+        assert!(diff(bad.as_bytes(), new.as_bytes(), io::sink()).is_err());
+        let negative = "client,available,held,total,locked\n1,-1,1,0,false\n";
+        assert_eq!(
+            diff(negative.as_bytes(), new.as_bytes(), io::sink()).unwrap(),
+            4
+        );
+        let bad = "client,available,held,total,locked\n1,1,-1,0,false\n";
+        assert!(diff(bad.as_bytes(), new.as_bytes(), io::sink()).is_err());
+    }
+}
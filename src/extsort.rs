@@ -0,0 +1,185 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::amount::{Amount, SignedAmount};
+use crate::process::ClientSummary;
+use crate::tx::ClientID;
+
+// how many summaries are sorted in memory at once by `--external-sort`
+// unless `--sort-chunk` says otherwise; that's more than the 65536 client
+// ids there can be, so by default everything fits in one chunk
+pub const CHUNK_LEN: usize = 100_000;
+
+type Row = (
+    ClientID,
+    SignedAmount,
+    Amount,
+    SignedAmount,
+    bool,
+    Amount,
+    SignedAmount,
+    u64,
+    u64,
+    u64,
+    u64,
+);
+
+/// Sorts summaries by client id holding at most `chunk_len` of them in
+/// memory, at least one: sorted chunks are spilled to temporary files,
+/// which are then merged. The files are removed once the returned iterator
+/// is dropped.
+pub fn sort_summaries(
+    summaries: impl Iterator<Item = ClientSummary>,
+    chunk_len: usize,
+) -> Result<Merge, csv::Error> {
+    let mut merge = Merge {
+        paths: Vec::new(),
+        readers: Vec::new(),
+        heads: Vec::new(),
+        heap: BinaryHeap::new(),
+    };
+    let chunk_len = chunk_len.max(1);
+    let mut chunk = Vec::with_capacity(chunk_len);
+    let mut summaries = summaries.peekable();
+    while summaries.peek().is_some() {
+        chunk.extend(summaries.by_ref().take(chunk_len));
+        chunk.sort_unstable_by_key(|summary: &ClientSummary| summary.id);
+        merge.spill(chunk.drain(..))?;
+    }
+    for i in 0..merge.paths.len() {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(&merge.paths[i])?;
+        merge.readers.push(reader.into_deserialize());
+        merge.heads.push(None);
+        merge.advance(i)?;
+    }
+    Ok(merge)
+}
+
+/// Sorted summaries merged from the spilled chunks.
+pub struct Merge {
+    paths: Vec<PathBuf>,
+    readers: Vec<csv::DeserializeRecordsIntoIter<File, Row>>,
+    // the next summary of each chunk
+    heads: Vec<Option<ClientSummary>>,
+    // ids of the heads, with the index of their chunk
+    heap: BinaryHeap<Reverse<(ClientID, usize)>>,
+}
+
+impl Merge {
+    fn spill(
+        &mut self,
+        chunk: impl Iterator<Item = ClientSummary>,
+    ) -> Result<(), csv::Error> {
+        static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "payments-sort-{}-{}.csv",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        // remember it first so that it is removed even if writing fails
+        self.paths.push(path.clone());
+        let mut wtr = csv::Writer::from_path(path)?;
+        for s in chunk {
+            let row: Row = (
+                s.id,
+                s.available,
+                s.held,
+                s.total,
+                s.locked,
+                s.opening,
+                s.closing,
+                s.disputes,
+                s.deposit_count,
+                s.withdrawal_count,
+                s.active_disputes,
+            );
+            wtr.serialize(row)?
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    fn advance(&mut self, i: usize) -> Result<(), csv::Error> {
+        self.heads[i] = match self.readers[i].next().transpose()? {
+            Some(row) => {
+                self.heap.push(Reverse((row.0, i)));
+                Some(ClientSummary {
+                    id: row.0,
+                    available: row.1,
+                    held: row.2,
+                    total: row.3,
+                    locked: row.4,
+                    opening: row.5,
+                    closing: row.6,
+                    disputes: row.7,
+                    deposit_count: row.8,
+                    withdrawal_count: row.9,
+                    active_disputes: row.10,
+                })
+            }
+            None => None,
+        };
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<ClientSummary, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let summary = self.heads[i].take().expect("heap entry without head");
+        Some(self.advance(i).map(|()| summary))
+    }
+}
+
+impl Drop for Merge {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen};
+
+    #[test]
+    fn test_sort_summaries() {
+        let mut g = Gen::new(100);
+        let summaries: Vec<ClientSummary> =
+            (0..25).map(|_| ClientSummary::arbitrary(&mut g)).collect();
+        let merge = sort_summaries(summaries.iter().cloned(), 3).unwrap();
+        let paths = merge.paths.clone();
+        assert_eq!(paths.len(), 9);
+
+        let sorted: Vec<ClientSummary> =
+            merge.collect::<Result<_, _>>().unwrap();
+        let mut expected = summaries;
+        expected.sort_by_key(|summary| summary.id);
+        assert_eq!(
+            sorted.iter().map(|s| s.id).collect::<Vec<_>>(),
+            expected.iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        // ties between chunks may come out in either order
+        for s in &expected {
+            assert!(sorted.contains(s));
+        }
+        assert!(paths.iter().all(|path| !path.exists()));
+
+        let merge = sort_summaries(expected.into_iter(), 0).unwrap();
+        assert_eq!(merge.paths.len(), 25);
+
+        assert_eq!(
+            sort_summaries(Vec::new().into_iter(), 3).unwrap().count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+
+/// Reads like `tail -f`: at the end of the input it waits for more to be
+/// appended instead of reporting EOF, so reading only ends with an error.
+pub struct Follow<R> {
+    inner: R,
+    poll_interval: Duration,
+}
+
+impl<R: Read> Follow<R> {
+    pub fn new(inner: R, poll_interval: Duration) -> Self {
+        Self {
+            inner,
+            poll_interval,
+        }
+    }
+}
+
+impl<R: Read> Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.inner.read(buf)? {
+                0 => thread::sleep(self.poll_interval),
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+
+    #[test]
+    fn test_follow() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-follow-{}.csv", std::process::id()));
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1\n")
+            .unwrap();
+        let file = File::open(&path).unwrap();
+        let follow = Follow::new(file, Duration::from_millis(5));
+
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            let mut file =
+                OpenOptions::new().append(true).open(writer_path).unwrap();
+            for chunk in &["deposit,1,2,", "2\nwithdrawal,1,3,1\n"] {
+                thread::sleep(Duration::from_millis(50));
+                file.write_all(chunk.as_bytes()).unwrap();
+            }
+        });
+
+        // only ends because we stop asking after three rows
+        let rows: Vec<String> = csv::Reader::from_reader(follow)
+            .records()
+            .take(3)
+            .map(|record| record.unwrap().iter().collect::<Vec<_>>().join(","))
+            .collect();
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rows,
+            vec!["deposit,1,1,1", "deposit,1,2,2", "withdrawal,1,3,1"]
+        );
+    }
+}
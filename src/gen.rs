@@ -0,0 +1,168 @@
+use std::io;
+
+use crate::amount::Amount;
+use crate::tx::{ClientID, Tx, TxID};
+
+/// A source of random numbers for `TxGenerator`.
+pub trait Random {
+    fn next_u64(&mut self) -> u64;
+}
+
+/// A small seeded generator (splitmix64), good enough for test data and
+/// reproducible from the seed.
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+}
+
+impl<R: Random + ?Sized> Random for &mut R {
+    fn next_u64(&mut self) -> u64 {
+        (**self).next_u64()
+    }
+}
+
+impl Random for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Endless random transactions of clients `0..clients`, with ids counting
+/// up from 0. Disputes, resolves and chargebacks refer to an earlier
+/// deposit or withdrawal of the same client; the first transaction is
+/// always a deposit. Amounts are at most `max_amount`.
+pub struct TxGenerator<R> {
+    rng: R,
+    clients: u16,
+    max_amount: Amount,
+    recorded: Vec<(ClientID, TxID)>,
+    next_tx_id: u64,
+}
+
+impl<R: Random> TxGenerator<R> {
+    /// Panics if `clients` is 0.
+    pub fn new(rng: R, clients: u16, max_amount: Amount) -> Self {
+        assert!(clients > 0, "no clients to generate transactions for");
+        Self {
+            rng,
+            clients,
+            max_amount,
+            recorded: Vec::new(),
+            next_tx_id: 0,
+        }
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.rng.next_u64() % n
+    }
+
+    fn amount(&mut self) -> Amount {
+        let max = self.max_amount.to_minor_units();
+        let units = match max.checked_add(1) {
+            Some(n) => self.below(n),
+            None => self.rng.next_u64(),
+        };
+        Amount::from_minor_units(units)
+    }
+}
+
+impl<R: Random> Iterator for TxGenerator<R> {
+    type Item = Tx;
+
+    fn next(&mut self) -> Option<Tx> {
+        let client_id = (self.below(self.clients.into()) as u16).into();
+        let tx_id = self.next_tx_id.into();
+        self.next_tx_id += 1;
+        // weighted like the property tests: deposits, withdrawals, disputes
+        // and resolves alike, chargebacks rarely
+        let tx = match (self.below(41), self.recorded.len()) {
+            (0..=9, _) | (_, 0) => {
+                self.recorded.push((client_id, tx_id));
+                Tx::Deposit {
+                    client_id,
+                    tx_id,
+                    amount: self.amount(),
+                }
+            }
+            (10..=19, _) => {
+                self.recorded.push((client_id, tx_id));
+                Tx::Withdrawal {
+                    client_id,
+                    tx_id,
+                    amount: self.amount(),
+                }
+            }
+            (n, len) => {
+                let i = self.below(len as u64) as usize;
+                let (client_id, tx_id) = self.recorded[i];
+                match n {
+                    20..=29 => Tx::Dispute { client_id, tx_id },
+                    30..=39 => Tx::Resolve { client_id, tx_id },
+                    _ => Tx::Chargeback { client_id, tx_id },
+                }
+            }
+        };
+        Some(tx)
+    }
+}
+
+/// Writes `txs` as CSV with a `type,client,tx,amount` header, as read by
+/// `process`.
+pub fn write_txs<W: io::Write>(
+    output: W,
+    txs: impl Iterator<Item = Tx>,
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+    for tx in txs {
+        let amount = match tx {
+            Tx::Deposit { amount, .. } | Tx::Withdrawal { amount, .. } => {
+                Some(amount)
+            }
+            _ => None,
+        };
+        wtr.serialize((tx.kind(), tx.client_id(), tx.tx_id(), amount))?
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::TxProcessor;
+    use crate::source::{process_source, CsvSource};
+
+    #[test]
+    fn test_generated_txs_parse() {
+        let max_amount = "1000".parse().unwrap();
+        let gen = TxGenerator::new(SplitMix64::new(7), 10, max_amount);
+        let txs: Vec<Tx> = gen.take(2000).collect();
+        let mut output = Vec::new();
+        write_txs(&mut output, txs.iter().copied()).unwrap();
+
+        let parsed: Vec<Tx> = csv::Reader::from_reader(&output[..])
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parsed, txs);
+        assert!(txs.iter().all(|tx| tx.client_id() < 10.into()));
+        let mut tx_proc = TxProcessor::new();
+        let mut source = CsvSource::new(&output[..]);
+        // some are rejected, e.g. withdrawals exceeding available, but all
+        // of them parse
+        assert!(process_source(&mut tx_proc, &mut source) < txs.len());
+        assert!(tx_proc.client_summaries().count() <= 10);
+
+        // the same seed gives the same transactions
+        let again = TxGenerator::new(SplitMix64::new(7), 10, max_amount);
+        assert!(again.take(2000).eq(txs.into_iter()));
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// for every read of the response, so a slow but steady body is fine
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A bare HTTP/1.0 GET, which is all we need to stream a CSV file from an
+// object store. HTTP/1.0 keeps the server from using chunked encoding, so
+// the body is just the rest of the stream. Only plain http is supported.
+pub fn get(url: &str) -> io::Result<impl Read> {
+    get_timeout(url, READ_TIMEOUT)
+}
+
+fn get_timeout(url: &str, read_timeout: Duration) -> io::Result<impl Read> {
+    let rest = if let Some(rest) = url.strip_prefix("http://") {
+        rest
+    } else if url.starts_with("https://") {
+        return Err(invalid_input(format!("https isn't supported: {}", url)));
+    } else {
+        return Err(invalid_input(format!("not an http URL: {}", url)));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = connect(&addr)?;
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(read_timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, authority
+    )?;
+
+    let mut rdr = BufReader::new(stream);
+    let mut status_line = String::new();
+    rdr.read_line(&mut status_line)?;
+    let status_line = status_line.trim_end();
+    if status_line.split(' ').nth(1) != Some("200") {
+        return Err(io::Error::other(format!(
+            "GET {} failed: {}",
+            url, status_line
+        )));
+    }
+    loop {
+        let mut header = String::new();
+        if rdr.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(rdr)
+}
+
+// the first of `addr`'s addresses that accepts a connection in time
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| invalid_input(format!("{} has no addresses", addr))))
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // serves a single request with the given raw response, returns the URL
+    pub fn serve_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut rdr = BufReader::new(stream);
+            let mut line = String::new();
+            while rdr.read_line(&mut line).unwrap() > 2 {
+                line.clear()
+            }
+            rdr.get_mut().write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/transactions.csv", addr)
+    }
+
+    #[test]
+    fn test_get() {
+        let url = serve_once(
+            "HTTP/1.0 200 OK\r\nContent-Type: text/csv\r\n\r\na,b\n1,2\n",
+        );
+        let mut body = String::new();
+        get(&url).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "a,b\n1,2\n");
+    }
+
+    #[test]
+    fn test_get_not_found() {
+        let url = serve_once("HTTP/1.0 404 Not Found\r\n\r\n");
+        let err = get(&url).err().unwrap();
+        assert!(err.to_string().ends_with("failed: HTTP/1.0 404 Not Found"));
+    }
+
+    #[test]
+    fn test_get_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/x.csv", listener.local_addr().unwrap());
+        // accepted by the OS, but never answered
+        let err = get_timeout(&url, Duration::from_millis(50)).err().unwrap();
+        assert!(
+            matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            "{:?}",
+            err
+        );
+        drop(listener);
+    }
+
+    #[test]
+    fn test_get_https() {
+        let err = get("https://example.com/x.csv").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,294 @@
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+// just enough JSON to emit and read flat records, so we don't need a JSON
+// library
+#[derive(Default)]
+pub struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self { buf: String::new() }
+    }
+
+    pub fn str(self, key: &str, value: &str) -> Self {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quote(&mut quoted, value);
+        self.raw(key, &quoted)
+    }
+
+    pub fn num(self, key: &str, value: impl Display) -> Self {
+        self.raw(key, &value.to_string())
+    }
+
+    pub fn bool(self, key: &str, value: bool) -> Self {
+        self.raw(key, if value { "true" } else { "false" })
+    }
+
+    fn raw(mut self, key: &str, value: &str) -> Self {
+        self.buf.push(if self.buf.is_empty() { '{' } else { ',' });
+        quote(&mut self.buf, key);
+        self.buf.push(':');
+        self.buf.push_str(value);
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        if self.buf.is_empty() {
+            self.buf.push('{');
+        }
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+fn quote(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(buf, "\\u{:04x}", c as u32).unwrap()
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseJsonError {
+    UnexpectedEnd,
+    // with its byte offset
+    UnexpectedChar(char, usize),
+    // an object or array as a value
+    Nested(usize),
+    InvalidEscape(usize),
+}
+
+impl Display for ParseJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::UnexpectedEnd => write!(f, "unexpected end of JSON"),
+            Self::UnexpectedChar(c, at) => {
+                write!(f, "unexpected {:?} at {} in JSON", c, at)
+            }
+            Self::Nested(at) => write!(f, "nested value at {} in JSON", at),
+            Self::InvalidEscape(at) => {
+                write!(f, "invalid escape at {} in JSON", at)
+            }
+        }
+    }
+}
+
+impl Error for ParseJsonError {}
+
+/// Parses an object whose values are all strings, numbers, booleans or
+/// `null` into its keys and values: strings unescaped, numbers and
+/// booleans as written, `null` as `None`. Numbers are only checked for
+/// their characters, whoever reads them parses them properly.
+pub fn parse_flat_object(
+    s: &str,
+) -> Result<Vec<(String, Option<String>)>, ParseJsonError> {
+    let mut p = Parser { s, pos: 0 };
+    p.expect('{')?;
+    let mut fields = Vec::new();
+    if !p.eat('}') {
+        loop {
+            p.skip_ws();
+            let key = p.string()?;
+            p.expect(':')?;
+            fields.push((key, p.scalar()?));
+            if p.eat('}') {
+                break;
+            }
+            p.expect(',')?;
+        }
+    }
+    p.skip_ws();
+    match p.peek() {
+        None => Ok(fields),
+        Some(c) => Err(ParseJsonError::UnexpectedChar(c, p.pos)),
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Result<char, ParseJsonError> {
+        let c = self.peek().ok_or(ParseJsonError::UnexpectedEnd)?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn unexpected(&self) -> ParseJsonError {
+        match self.peek() {
+            Some(c) => ParseJsonError::UnexpectedChar(c, self.pos),
+            None => ParseJsonError::UnexpectedEnd,
+        }
+    }
+
+    // skips whitespace before `c`
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseJsonError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseJsonError> {
+        if self.peek() != Some('"') {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let at = self.pos;
+            let c = match self.bump()? {
+                '"' => return Ok(out),
+                '\\' => match self.bump()? {
+                    '"' => '"',
+                    '\\' => '\\',
+                    '/' => '/',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    // surrogate pairs aren't needed for our fields
+                    'u' => {
+                        let c = self
+                            .s
+                            .get(self.pos..self.pos + 4)
+                            .filter(|hex| {
+                                hex.bytes().all(|b| b.is_ascii_hexdigit())
+                            })
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or(ParseJsonError::InvalidEscape(at))?;
+                        self.pos += 4;
+                        c
+                    }
+                    _ => return Err(ParseJsonError::InvalidEscape(at)),
+                },
+                c if (c as u32) < 0x20 => {
+                    return Err(ParseJsonError::UnexpectedChar(c, at))
+                }
+                c => c,
+            };
+            out.push(c)
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Option<String>, ParseJsonError> {
+        self.skip_ws();
+        let start = self.pos;
+        match self.peek() {
+            Some('"') => self.string().map(Some),
+            Some('{' | '[') => Err(ParseJsonError::Nested(start)),
+            Some(c) if c == '-' || c.is_ascii_alphanumeric() => {
+                while self.peek().is_some_and(|c| {
+                    c.is_ascii_alphanumeric() || "+-.".contains(c)
+                }) {
+                    self.pos += 1;
+                }
+                let word = &self.s[start..self.pos];
+                let number = (c == '-' || c.is_ascii_digit())
+                    && word
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+                match word {
+                    "null" => Ok(None),
+                    "true" | "false" => Ok(Some(word.to_string())),
+                    _ if number => Ok(Some(word.to_string())),
+                    _ => Err(ParseJsonError::UnexpectedChar(c, start)),
+                }
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object() {
+        assert_eq!(Object::new().finish(), "{}");
+        assert_eq!(
+            Object::new()
+                .str("a", "x\"y\n")
+                .num("b", 12)
+                .bool("c", false)
+                .finish(),
+            r#"{"a":"x\"y\n","b":12,"c":false}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_flat_object() {
+        let field = |k: &str, v: Option<&str>| (k.into(), v.map(String::from));
+        assert_eq!(parse_flat_object(" { } "), Ok(vec![]));
+        assert_eq!(
+            parse_flat_object(
+                r#"{"a":"x\"y\n\u00e9", "b" : -1.5e3,"c":true,"d":null}"#
+            ),
+            Ok(vec![
+                field("a", Some("x\"y\né")),
+                field("b", Some("-1.5e3")),
+                field("c", Some("true")),
+                field("d", None),
+            ])
+        );
+        // what `Object` writes reads back
+        let written = Object::new().str("k", "\t\\").num("n", 7).finish();
+        assert_eq!(
+            parse_flat_object(&written),
+            Ok(vec![field("k", Some("\t\\")), field("n", Some("7"))])
+        );
+
+        for (s, err) in &[
+            ("", ParseJsonError::UnexpectedEnd),
+            (r#"{"a":1"#, ParseJsonError::UnexpectedEnd),
+            (r#"{"a":1,}"#, ParseJsonError::UnexpectedChar('}', 7)),
+            (r#"{"a":[1]}"#, ParseJsonError::Nested(5)),
+            (r#"{"a":"\x"}"#, ParseJsonError::InvalidEscape(6)),
+            (r#"{"a":"\u12"}"#, ParseJsonError::InvalidEscape(6)),
+            (r#"{"a":nope}"#, ParseJsonError::UnexpectedChar('n', 5)),
+            (r#"{"a":1} x"#, ParseJsonError::UnexpectedChar('x', 8)),
+            (r#"{a:1}"#, ParseJsonError::UnexpectedChar('a', 1)),
+        ] {
+            assert_eq!(parse_flat_object(s).as_ref(), Err(err), "{}", s);
+        }
+    }
+}
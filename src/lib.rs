@@ -0,0 +1,52 @@
+//! Processing of deposits, withdrawals, disputes, resolves and chargebacks
+//! into client balances; the `payments` binary is a CSV front end to it.
+//!
+//! ```
+//! use payments::{ClientSummary, Tx, TxProcessor};
+//!
+//! let txs = vec![
+//!     Tx::Deposit {
+//!         client_id: 1.into(),
+//!         tx_id: 1.into(),
+//!         amount: "10".parse().unwrap(),
+//!     },
+//!     Tx::Withdrawal {
+//!         client_id: 1.into(),
+//!         tx_id: 2.into(),
+//!         amount: "2.5".parse().unwrap(),
+//!     },
+//!     Tx::Dispute {
+//!         client_id: 1.into(),
+//!         tx_id: 1.into(),
+//!     },
+//! ];
+//! let mut tx_proc = TxProcessor::new();
+//! for tx in &txs {
+//!     if let Err(err) = tx_proc.process(tx) {
+//!         println!("skipped: {}", err);
+//!     }
+//! }
+//! let summaries: Vec<ClientSummary> = tx_proc.client_summaries().collect();
+//! // the dispute needed 10 available, and only 7.5 were left
+//! assert_eq!(summaries[0].available.to_string(), "7.5");
+//! assert_eq!(summaries[0].held.to_string(), "0");
+//! ```
+
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
+pub mod amount;
+pub mod diff;
+pub mod extsort;
+pub mod gen;
+pub mod json;
+pub mod parallel;
+pub mod process;
+pub mod source;
+pub mod tx;
+
+pub use amount::Amount;
+pub use process::{ClientSummary, TxProcessingError, TxProcessor};
+pub use source::{process_csv, ProcessError};
+pub use tx::{ClientID, Tx, TxID};
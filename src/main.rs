@@ -1,44 +1,1474 @@
-#[cfg(test)]
-#[macro_use]
-extern crate quickcheck;
-
-mod amount;
-mod process;
-mod tx;
+mod cli;
+mod follow;
+#[cfg(feature = "http-input")]
+mod http;
 
-use process::*;
+use cli::{Command, Format, Options};
+use payments::amount::Amount;
+use payments::diff::{self, write_summaries};
+use payments::gen::{write_txs, SplitMix64, TxGenerator};
+use payments::parallel::process_parallel;
+use payments::process::*;
+use payments::source::{
+    process_source_with, InvalidRecord, NdjsonLineError, NdjsonRecords,
+    Outcome, RecordSource, NDJSON_FIELDS,
+};
+use payments::tx::*;
+use payments::{extsort, json};
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
 
 fn main() -> Result<(), csv::Error> {
-    let input_path = std::env::args().nth(1).expect("no path to input given");
+    let opts = Options::parse(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+
+    if let (Command::Diff, Some(old), Some(new)) =
+        (opts.command, &opts.input_path, &opts.diff_path)
+    {
+        let old = std::fs::File::open(old)?;
+        let new = std::fs::File::open(new)?;
+        let differing = ignore_broken_pipe(diff::diff(old, new, io::stdout()))?;
+        if differing > 0 {
+            std::process::exit(1)
+        }
+        return Ok(());
+    }
+    if let (Command::Gen, Some(clients), Some(txs)) =
+        (opts.command, opts.gen_clients, opts.gen_txs)
+    {
+        let seed = opts
+            .seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        let output: Box<dyn io::Write> = match opts.output_path {
+            Some(ref path) => Box::new(std::fs::File::create(path)?),
+            None => Box::new(io::stdout()),
+        };
+        return ignore_broken_pipe(generate(clients, txs, seed, output));
+    }
+
+    let input: Box<dyn io::Read> = match opts.input_path {
+        Some(ref path) if opts.follow => {
+            let interval = opts.poll_interval_ms.unwrap_or(1000);
+            Box::new(follow::Follow::new(
+                std::fs::File::open(path)?,
+                Duration::from_millis(interval),
+            ))
+        }
+        Some(ref path) => open_input(path)?,
+        None => Box::new(io::stdin()),
+    };
+    let report = match opts.parallel {
+        Some(shards) => run_parallel(&opts, shards, input, io::stdout()),
+        None => run(&opts, input, io::stdout()),
+    };
+    let report = ignore_broken_pipe(report)?;
+    if opts.timing {
+        eprintln!("{}", timing(&report));
+    }
+    if let Some(memory) = report.memory {
+        eprintln!("{}", memory);
+    }
+    if report.memory_exceeded {
+        eprintln!(
+            "stopped after {} rows: over the --max-memory limit, the summary \
+             is partial",
+            report.rows
+        );
+        std::process::exit(EXIT_MEMORY_EXCEEDED)
+    }
+    if opts.command == Command::Validate {
+        print!("{}", check_report(&report));
+        if report.rejected > 0 {
+            std::process::exit(1)
+        }
+    }
+    Ok(())
+}
+
+// exit code when `--max-memory` stopped reading early
+const EXIT_MEMORY_EXCEEDED: i32 = 3;
+// how often to compare retained transactions against `--max-memory`
+const MEMORY_CHECK_EVERY: usize = 1000;
+// rough memory per retained transaction: its ledger entry, its owner entry
+// and the hash tables' overhead
+const BYTES_PER_RETAINED_TX: usize = 64;
+
+#[derive(Debug, Default, PartialEq)]
+struct Report {
+    rows: usize,
+    rejected: usize,
+    // by `TxProcessingError::kind` and the like
+    rejected_by_kind: BTreeMap<&'static str, usize>,
+    // spent reading and processing the rows
+    elapsed: Duration,
+    // reading stopped early because of `--max-memory`
+    memory_exceeded: bool,
+    // with `--mem-report`, of the state after the last row
+    memory: Option<MemoryReport>,
+}
+
+// what `validate` prints instead of the summary
+fn check_report(report: &Report) -> String {
+    let mut lines = format!(
+        "rows: {}\naccepted: {}\nrejected: {}\n",
+        report.rows,
+        report.rows - report.rejected,
+        report.rejected
+    );
+    for (kind, n) in &report.rejected_by_kind {
+        lines += &format!("  {}: {}\n", kind, n);
+    }
+    lines
+}
+
+fn timing(report: &Report) -> String {
+    let secs = report.elapsed.as_secs_f64();
+    let mut line = format!("processed {} rows in {:.3}s", report.rows, secs);
+    if secs > 0.0 {
+        line += &format!(", {:.0} rows/s", report.rows as f64 / secs);
+    }
+    line
+}
+
+type RowResult = Result<csv::StringRecord, Box<dyn Error>>;
+
+// The input rows, kept raw as well as parsed: rejected ones are copied out
+// as they were read.
+struct Rows<I> {
+    records: I,
+    headers: csv::StringRecord,
+    type_column: Option<usize>,
+    numeric_kinds: bool,
+    strict_amounts: bool,
+    // the last row read, unless it was unreadable
+    record: Option<csv::StringRecord>,
+    line: Option<u64>,
+}
+
+impl<I> RecordSource for Rows<I>
+where
+    I: Iterator<Item = RowResult>,
+{
+    fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(err) => {
+                self.line = match err.downcast_ref::<csv::Error>() {
+                    Some(err) => err.position().map(|pos| pos.line()),
+                    None => {
+                        err.downcast_ref::<NdjsonLineError>().map(|e| e.line)
+                    }
+                };
+                self.record = None;
+                return Some(Err(err));
+            }
+        };
+        self.line = record.position().map(|pos| pos.line());
+        let record = self.record.insert(record);
+        let named = match self.type_column {
+            Some(i) if self.numeric_kinds => name_kind(record, i),
+            _ => Ok(record.clone()),
+        };
+        let tx = named
+            .map_err(|message| InvalidRecord {
+                kind: "unknown_kind",
+                message,
+            })
+            .and_then(|record| {
+                let headers = Some(&self.headers);
+                Tx::from_record(&record, headers, self.strict_amounts).map_err(
+                    |err| InvalidRecord {
+                        kind: err.kind(),
+                        message: err.to_string(),
+                    },
+                )
+            });
+        Some(tx.map_err(Box::from))
+    }
+
+    fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    fn record(&self) -> Option<&csv::StringRecord> {
+        self.record.as_ref()
+    }
+
+    fn headers(&self) -> Option<&csv::StringRecord> {
+        Some(&self.headers)
+    }
+}
+
+// `record` with the kind code in `column` replaced by the kind's name
+fn name_kind(
+    record: &csv::StringRecord,
+    column: usize,
+) -> Result<csv::StringRecord, String> {
+    let code = record.get(column).unwrap_or("");
+    let code = code.parse::<u8>().map_err(|_| {
+        format!("transaction kind code {:?} isn't a number", code)
+    })?;
+    let kind = TxKind::try_from(code).map_err(|err| err.to_string())?;
+    Ok(record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if i == column {
+                kind.to_string()
+            } else {
+                field.to_string()
+            }
+        })
+        .collect())
+}
+
+// largest amount of generated deposits and withdrawals
+const GEN_MAX_AMOUNT: &str = "1000";
+
+fn generate<W: io::Write>(
+    clients: u16,
+    txs: usize,
+    seed: u64,
+    output: W,
+) -> Result<(), csv::Error> {
+    let max_amount = GEN_MAX_AMOUNT.parse().expect("valid amount");
+    let gen = TxGenerator::new(SplitMix64::new(seed), clients, max_amount);
+    write_txs(output, gen.take(txs))
+}
+
+// the reader of our output went away (e.g. `| head`), which is not an error
+fn ignore_broken_pipe<T: Default>(
+    result: Result<T, csv::Error>,
+) -> Result<T, csv::Error> {
+    match result {
+        Err(err) => match err.kind() {
+            csv::ErrorKind::Io(io_err)
+                if io_err.kind() == io::ErrorKind::BrokenPipe =>
+            {
+                Ok(T::default())
+            }
+            _ => Err(err),
+        },
+        ok => ok,
+    }
+}
 
-    let mut tx_proc = TxProcessor::new();
-    let mut rdr = csv::Reader::from_path(input_path)?;
-    for tx in rdr.deserialize() {
+fn open_input(path: &str) -> io::Result<Box<dyn io::Read>> {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        #[cfg(feature = "http-input")]
+        return Ok(Box::new(http::get(path)?));
+        #[cfg(not(feature = "http-input"))]
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "URL input needs the http-input feature",
+        ));
+    }
+    Ok(Box::new(std::fs::File::open(path)?))
+}
+
+fn run<R: io::Read, W: io::Write>(
+    opts: &Options,
+    input: R,
+    output: W,
+) -> Result<Report, csv::Error> {
+    run_source(opts, &mut rows(opts, input)?, output)
+}
+
+// the rows of `input` in the format of `opts`
+fn rows<'a, R: io::Read + 'a>(
+    opts: &Options,
+    input: R,
+) -> Result<Rows<Box<dyn Iterator<Item = RowResult> + 'a>>, csv::Error> {
+    let (headers, records): (_, Box<dyn Iterator<Item = _>>) = match opts.format
+    {
+        Format::Csv => {
+            let mut rdr = csv::Reader::from_reader(input);
+            let headers = rdr.headers()?.clone();
+            let records = rdr.into_records().map(|r| r.map_err(Box::from));
+            (headers, Box::new(records))
+        }
+        Format::Json => {
+            let records = NdjsonRecords::new(io::BufReader::new(input));
+            (
+                csv::StringRecord::from(&NDJSON_FIELDS[..]),
+                Box::new(records),
+            )
+        }
+    };
+    Ok(Rows {
+        type_column: headers.iter().position(|h| h == "type"),
+        headers,
+        records,
+        numeric_kinds: opts.numeric_kinds,
+        strict_amounts: opts.strict_amounts,
+        record: None,
+        line: None,
+    })
+}
+
+// `run` with `--parallel`: the rows are read here and their transactions
+// processed on `shards` threads, which only the summary comes out of
+fn run_parallel<R: io::Read, W: io::Write>(
+    opts: &Options,
+    shards: usize,
+    input: R,
+    output: W,
+) -> Result<Report, csv::Error> {
+    let mut source = rows(opts, input)?;
+    let mut report = Report::default();
+    let reject = |report: &mut Report, kind| {
+        report.rejected += 1;
+        *report.rejected_by_kind.entry(kind).or_default() += 1;
+    };
+    let new_processor = || {
+        let mut builder = TxProcessor::builder();
+        if let Some(seed) = opts.seed {
+            builder = builder.hash_seed(seed);
+        }
+        builder
+            .allow_negative_available(opts.allow_negative)
+            .build()
+    };
+    let started = Instant::now();
+    let txs = std::iter::from_fn(|| loop {
+        let tx = source.next_tx()?;
+        report.rows += 1;
         match tx {
-            Ok(tx) => {
-                if let Err(err) = tx_proc.process(&tx) {
-                    eprintln!("failed to process {:?}: {}", tx, err)
+            Ok(tx) => return Some(tx),
+            Err(err) => {
+                let kind = match err.downcast_ref::<InvalidRecord>() {
+                    Some(invalid) => {
+                        eprintln!("failed to parse tx: {}", err);
+                        invalid.kind
+                    }
+                    None => {
+                        eprintln!("failed to read row: {}", err);
+                        "unreadable"
+                    }
+                };
+                reject(&mut report, kind)
+            }
+        }
+    });
+    let parallel = process_parallel(txs, shards, new_processor);
+    report.elapsed = started.elapsed();
+    for tx in &parallel.skipped_transfers {
+        eprintln!("skipping transfer between shards {:?}", tx);
+        reject(&mut report, "cross_shard_transfer");
+    }
+    for tx in &parallel.reused_tx_ids {
+        eprintln!("skipping {:?}: a client of another shard used its id", tx);
+        reject(&mut report, "duplicate_tx_id");
+    }
+    let columns = diff::Columns {
+        statement: false,
+        verbose: opts.verbose,
+        decimals: opts.decimals,
+    };
+    let summaries = parallel.summaries.into_iter().map(Ok);
+    write_summaries(output, summaries, columns)?;
+    Ok(report)
+}
+
+// `run` on the transactions of any source; rejected records are copied
+// out only from sources that keep them
+fn run_source<S: RecordSource, W: io::Write>(
+    opts: &Options,
+    source: &mut S,
+    mut output: W,
+) -> Result<Report, csv::Error> {
+    let mut builder = TxProcessor::builder();
+    if let Some(ref path) = opts.opening_balances_path {
+        builder = builder.opening_balances(read_opening_balances(path)?);
+    }
+    if let Some(seed) = opts.seed {
+        builder = builder.hash_seed(seed);
+    }
+    let mut tx_proc = builder
+        .allow_negative_available(opts.allow_negative)
+        .build();
+    let headers = source.headers().cloned().unwrap_or_default();
+    let tx_column = headers.iter().position(|h| h == "tx");
+    let client_column = headers.iter().position(|h| h == "client");
+
+    // rows are read raw so that rejected ones can be copied verbatim
+    let mut dead_letter = match opts.dead_letter_path {
+        Some(ref path) => {
+            let mut wtr = csv::Writer::from_path(path)?;
+            let mut dl_headers = headers.clone();
+            dl_headers.push_field("error");
+            wtr.write_record(&dl_headers)?;
+            Some(wtr)
+        }
+        None => None,
+    };
+
+    let mut errors_out = match opts.errors_out_path {
+        Some(ref path) => {
+            let mut wtr = csv::Writer::from_path(path)?;
+            wtr.write_record(["line", "tx_id", "error_kind", "message"])?;
+            Some(wtr)
+        }
+        None => None,
+    };
+
+    let columns = diff::Columns {
+        statement: opts.opening_balances_path.is_some(),
+        verbose: opts.verbose,
+        decimals: opts.decimals,
+    };
+    // checkpoints only make sense along with the final summary
+    let checkpoint_every = match opts.command {
+        Command::Process if !opts.emit_results => opts.checkpoint_every,
+        _ => None,
+    };
+
+    // for `--include-rejections`: the raw tx and client ids, and the error
+    let mut rejections: Vec<[String; 3]> = Vec::new();
+    let mut report = Report::default();
+    // the last transaction read, with its position
+    let mut last_tx: Option<(usize, Tx)> = None;
+    let skip_repeated = |record: usize, tx: &Tx| {
+        let repeated = opts.dedup_consecutive
+            && last_tx.is_some_and(|last| last == (record - 1, *tx));
+        last_tx = Some((record, *tx));
+        if repeated {
+            eprintln!("skipping repeated {:?}", tx);
+        }
+        !repeated
+    };
+    let mut on_row = |tx_proc: &TxProcessor,
+                      source: &S,
+                      outcome: Outcome<'_>|
+     -> Result<bool, csv::Error> {
+        report.rows += 1;
+        let (tx, rejection) = match outcome {
+            Outcome::Applied(tx) => (Some(tx), None),
+            Outcome::Skipped(_) => (None, None),
+            Outcome::Rejected(tx, rejection) => (tx, Some(rejection)),
+        };
+        if let (Some(tx), true) = (tx, opts.emit_results) {
+            let error = rejection.as_ref().map(|r| r.message.as_str());
+            writeln!(output, "{}", result_json(tx_proc, tx, error))?
+        }
+        if let Some(ref rejection) = rejection {
+            match (tx, rejection.kind) {
+                (Some(tx), _) => eprintln!(
+                    "failed to process {:?}: {}",
+                    tx, rejection.message
+                ),
+                (None, "unreadable") => {
+                    eprintln!("failed to read row: {}", rejection.message)
+                }
+                (None, _) => {
+                    eprintln!("failed to parse tx: {}", rejection.message)
                 }
             }
-            Err(err) => {
-                eprintln!("failed to parse tx: {}", err)
+            report.rejected += 1;
+            *report.rejected_by_kind.entry(rejection.kind).or_default() += 1;
+        }
+        let field = |i: Option<usize>| {
+            i.and_then(|i| source.record()?.get(i))
+                .unwrap_or("")
+                .to_string()
+        };
+        if let (Some(rejection), Some(wtr)) = (&rejection, errors_out.as_mut())
+        {
+            let line = match rejection.line {
+                Some(line) => line.to_string(),
+                None => String::new(),
+            };
+            let tx_id = match rejection.tx_id {
+                Some(tx_id) => Some(tx_id.as_u64()),
+                None => field(tx_column).trim().parse::<u64>().ok(),
+            };
+            let tx_id = tx_id.map_or(String::new(), |id| id.to_string());
+            let (kind, message) = (rejection.kind, &rejection.message);
+            wtr.write_record([&line[..], &tx_id, kind, message])?
+        }
+        // unreadable rows have nothing to copy
+        if let (Some(rejection), Some(record)) = (&rejection, source.record()) {
+            if opts.include_rejections {
+                rejections.push([
+                    field(tx_column),
+                    field(client_column),
+                    rejection.message.clone(),
+                ]);
+            }
+            if let Some(ref mut wtr) = dead_letter {
+                let mut rejected = record.clone();
+                rejected.push_field(&rejection.message);
+                wtr.write_record(&rejected)?
+            }
+        }
+        if checkpoint_every.is_some_and(|n| report.rows % n == 0) {
+            let summaries = tx_proc
+                .checked_client_summaries()
+                .filter_map(log_inconsistent)
+                .map(Ok);
+            write_summaries(&mut output, summaries, columns)?
+        }
+        if let Some(max) = opts.max_memory {
+            if report.rows % MEMORY_CHECK_EVERY == 0
+                && tx_proc.retained_txs().saturating_mul(BYTES_PER_RETAINED_TX)
+                    > max
+            {
+                report.memory_exceeded = true;
+                return Ok(false);
             }
         }
+        Ok(true)
+    };
+    let started = Instant::now();
+    let stopped = process_source_with(
+        &mut tx_proc,
+        source,
+        skip_repeated,
+        |tx_proc, source, outcome| match on_row(tx_proc, source, outcome) {
+            Ok(true) => ControlFlow::Continue(()),
+            Ok(false) => ControlFlow::Break(None),
+            Err(err) => ControlFlow::Break(Some(err)),
+        },
+    );
+    if let Err(Some(err)) = stopped {
+        return Err(err);
+    }
+    report.elapsed = started.elapsed();
+    if opts.mem_report {
+        report.memory = Some(tx_proc.memory_report());
+    }
+    if let Some(mut wtr) = dead_letter {
+        wtr.flush()?
+    }
+    if let Some(mut wtr) = errors_out {
+        wtr.flush()?
     }
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-    for ClientSummary {
-        id,
-        available,
-        held,
-        total,
-        locked,
-    } in tx_proc.client_summaries()
-    {
-        wtr.serialize((id, available, held, total, locked))?
+    if opts.emit_results || opts.command == Command::Validate {
+        return Ok(report);
+    }
+
+    if opts.external_sort {
+        let summaries = extsort::sort_summaries(
+            tx_proc
+                .client_summaries_unsorted()
+                .filter_map(log_inconsistent),
+            opts.sort_chunk_len.unwrap_or(extsort::CHUNK_LEN),
+        )?;
+        write_summaries(&mut output, summaries, columns)?;
+    } else {
+        let summaries = tx_proc
+            .checked_client_summaries()
+            .filter_map(log_inconsistent)
+            .map(Ok);
+        write_summaries(&mut output, summaries, columns)?;
+    }
+    if opts.include_rejections {
+        write_rejections(&mut output, &rejections)?
     }
+    if let Some(ref path) = opts.alert_negative_path {
+        let alerts = tx_proc.client_summaries().filter(negative_balance);
+        let file = std::fs::File::create(path)?;
+        write_summaries(file, alerts.map(Ok), columns)?
+    }
+
+    Ok(report)
+}
 
+// leaves out a client whose balances can't be summarized, with a message,
+// rather than cutting the summary short
+fn log_inconsistent(
+    summary: Result<ClientSummary, InconsistentClient>,
+) -> Option<ClientSummary> {
+    summary.map_err(|err| eprintln!("skipping {}", err)).ok()
+}
+
+// a section of its own after the summary, with its own header
+fn write_rejections<W: io::Write>(
+    output: W,
+    rejections: &[[String; 3]],
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record(["tx", "client", "error"])?;
+    for rejection in rejections {
+        wtr.write_record(rejection)?
+    }
+    wtr.flush()?;
     Ok(())
 }
+
+fn negative_balance(summary: &ClientSummary) -> bool {
+    summary.available.is_negative() || summary.total.is_negative()
+}
+
+#[derive(Deserialize)]
+struct OpeningBalance {
+    client: ClientID,
+    available: String,
+}
+
+fn read_opening_balances(
+    path: &str,
+) -> Result<HashMap<ClientID, Amount>, csv::Error> {
+    let mut balances = HashMap::new();
+    for row in csv::Reader::from_path(path)?.deserialize() {
+        let OpeningBalance { client, available } = row?;
+        let amount = available.parse().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad opening balance of client {}: {}", client, err),
+            )
+        })?;
+        balances.insert(client, amount);
+    }
+    Ok(balances)
+}
+
+// `error` is the message of the transaction's rejection, if any
+fn result_json(tx_proc: &TxProcessor, tx: &Tx, error: Option<&str>) -> String {
+    let client_id = tx.client_id();
+
+    let mut obj = json::Object::new()
+        .str("type", &tx.kind().to_string())
+        .num("client", client_id)
+        .num("tx", tx.tx_id());
+    obj = match error {
+        None => obj.str("result", "ok"),
+        Some(error) => obj.str("result", "error").str("error", error),
+    };
+    // a client is created on its first transaction, even a failed one
+    if let Some(s) = tx_proc.client_summary(client_id) {
+        obj = obj
+            .str("available", &s.available.to_string())
+            .str("held", &s.held.to_string())
+            .str("total", &s.total.to_string())
+            .bool("locked", s.locked);
+    }
+    obj.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payments::source::CsvSource;
+
+    #[test]
+    fn test_commands() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,30
+deposit,1,3,1.23456
+dispute,1,1,
+";
+        let mut output = Vec::new();
+        let report = run(&opts(), input.as_bytes(), &mut output).unwrap();
+        assert_eq!((report.rows, report.rejected), (4, 2));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,0,10,10,false\n"
+        );
+
+        let opts = Options {
+            command: Command::Validate,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(report.rejected, 2);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_run_source() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,30
+bogus,1,3,
+";
+        let mut source = CsvSource::new(input.as_bytes());
+        let mut output = Vec::new();
+        let report = run_source(&opts(), &mut source, &mut output).unwrap();
+        assert_eq!(report.rows, 3);
+        assert_eq!(report.rejected, 2);
+        // the source doesn't tell why it couldn't make a transaction
+        let kinds: Vec<_> = report.rejected_by_kind.into_iter().collect();
+        assert_eq!(kinds, [("insufficient_funds", 1), ("unreadable", 1)]);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,10,0,10,false\n"
+        );
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,30
+deposit,2,4,1.23456
+dispute,2,2,
+deposit,4,1,7
+deposit,3,5,1
+";
+        let mut sequential = Vec::new();
+        run(&opts(), input.as_bytes(), &mut sequential).unwrap();
+        let mut parallel = Vec::new();
+        let report =
+            run_parallel(&opts(), 2, input.as_bytes(), &mut parallel).unwrap();
+        assert_eq!(parallel, sequential);
+        assert_eq!(report.rows, 7);
+        // failing to process in a shard isn't counted
+        let kinds: Vec<_> = report.rejected_by_kind.into_iter().collect();
+        assert_eq!(kinds, [("duplicate_tx_id", 1), ("too_precise", 1)]);
+    }
+
+    #[test]
+    fn test_check() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,30
+deposit,1,3,1.23456
+withdrawal,1,4,50
+dispute,1,1,
+deposit,1,1,1
+";
+        let opts = Options {
+            command: Command::Validate,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(
+            check_report(&report),
+            "rows: 6
+accepted: 2
+rejected: 4
+  duplicate_tx_id: 1
+  insufficient_funds: 2
+  too_precise: 1
+"
+        );
+
+        let good = "type,client,tx,amount\ndeposit,1,1,10\n";
+        let report = run(&opts, good.as_bytes(), io::sink()).unwrap();
+        assert_eq!(report.rejected, 0);
+        assert_eq!(
+            check_report(&report),
+            "rows: 1\naccepted: 1\nrejected: 0\n"
+        );
+    }
+
+    fn run_str(opts: &Options, input: &str) -> String {
+        let mut output = Vec::new();
+        run(opts, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn opts() -> Options {
+        Options::default()
+    }
+
+    #[test]
+    fn test_errors_out() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-errors-{}.csv", std::process::id()));
+        let input = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,1,2,1.23456
+withdrawal,1,3,2
+refund,1,4,1
+deposit,1,x,1
+dispute,1,9,
+deposit,1,5,1
+";
+        let opts = Options {
+            errors_out_path: Some(path.to_str().unwrap().to_string()),
+            strict_amounts: true,
+            ..opts()
+        };
+        let output = run_str(&opts, input);
+        let errors = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(output.lines().nth(1), Some("1,2.5,0,2.5,false"));
+        let mut rdr = csv::Reader::from_reader(errors.as_bytes());
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["line", "tx_id", "error_kind", "message"]
+        );
+        let rows: Vec<Vec<String>> = rdr
+            .records()
+            .map(|row| row.unwrap().iter().map(String::from).collect())
+            .collect();
+        let kinds: Vec<[&str; 3]> = rows
+            .iter()
+            .map(|row| [&row[0][..], &row[1][..], &row[2][..]])
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ["3", "2", "too_precise"],
+                ["4", "3", "insufficient_funds"],
+                ["5", "4", "invalid_row"],
+                ["6", "", "invalid_row"],
+                ["7", "9", "deposit_not_found"],
+            ]
+        );
+        // with the position, like other parse errors
+        assert!(rows[0][3].starts_with("record 2 (line: 3, byte: "));
+        assert!(rows[0][3].ends_with("): unsupported precision of >4"));
+        assert_eq!(rows[1][3], "insufficient funds");
+    }
+
+    #[test]
+    fn test_json_errors_out() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-json-errors-{}.csv", std::process::id()));
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":1}
+
+{"type":"deposit","client":1,"tx":2,"amount":
+{"type":"withdrawal","client":1,"tx":3,"amount":2}
+"#;
+        let opts = Options {
+            format: Format::Json,
+            errors_out_path: Some(path.to_str().unwrap().to_string()),
+            ..opts()
+        };
+        run_str(&opts, input);
+        let errors = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = errors
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap())
+            .collect();
+        // the blank line counts
+        assert_eq!(lines, ["3", "4"]);
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-dead-letter-{}.csv", std::process::id()));
+        let input = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,1,2,1.23456
+withdrawal,1,3,2
+deposit,1,4,1
+";
+        let opts = Options {
+            dead_letter_path: Some(path.to_str().unwrap().to_string()),
+            ..opts()
+        };
+        let output = run_str(&opts, input);
+        let dead_letter = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(output.lines().nth(1), Some("1,2.5,0,2.5,false"));
+        let mut rdr = csv::Reader::from_reader(dead_letter.as_bytes());
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["type", "client", "tx", "amount", "error"]
+        );
+        let rows: Vec<csv::StringRecord> =
+            rdr.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        let fields = |row: &csv::StringRecord| {
+            row.iter().take(4).collect::<Vec<_>>().join(",")
+        };
+        assert_eq!(fields(&rows[0]), "deposit,1,2,1.23456");
+        assert!(rows[0][4].contains("unsupported precision"));
+        assert_eq!(fields(&rows[1]), "withdrawal,1,3,2");
+        assert_eq!(&rows[1][4], "insufficient funds");
+    }
+
+    #[test]
+    fn test_emit_results() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.5
+withdrawal,1,2,2
+dispute,2,1,
+";
+        let opts = Options {
+            emit_results: true,
+            ..opts()
+        };
+        let lines: Vec<String> =
+            run_str(&opts, input).lines().map(String::from).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"type":"deposit","client":1,"tx":1,"result":"ok","available":"1.5","held":"0","total":"1.5","locked":false}"#,
+                r#"{"type":"withdrawal","client":1,"tx":2,"result":"error","error":"insufficient funds","available":"1.5","held":"0","total":"1.5","locked":false}"#,
+                r#"{"type":"dispute","client":2,"tx":1,"result":"error","error":"transaction belongs to another client"}"#,
+            ]
+        );
+    }
+
+    struct FailingWriter(io::ErrorKind);
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(self.0.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(self.0.into())
+        }
+    }
+
+    #[test]
+    fn test_broken_pipe() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1\n";
+        let result = run(
+            &opts(),
+            input.as_bytes(),
+            FailingWriter(io::ErrorKind::BrokenPipe),
+        );
+        assert!(result.is_err());
+        assert!(ignore_broken_pipe(result).is_ok());
+
+        let result = run(
+            &opts(),
+            input.as_bytes(),
+            FailingWriter(io::ErrorKind::PermissionDenied),
+        );
+        assert!(ignore_broken_pipe(result).is_err());
+    }
+
+    #[test]
+    fn test_opening_balances() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-opening-{}.csv", std::process::id()));
+        std::fs::write(&path, "client,available\n1,100\n3,1.5\n").unwrap();
+        let opts = Options {
+            opening_balances_path: Some(path.to_str().unwrap().to_string()),
+            ..opts()
+        };
+        let input = "type,client,tx,amount
+deposit,1,1,20
+withdrawal,1,2,50.5
+deposit,2,3,7
+";
+        let output = run_str(&opts, input);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,opening,closing
+1,69.5,0,69.5,false,100,69.5
+2,7,0,7,false,0,7
+3,1.5,0,1.5,false,1.5,1.5
+"
+        );
+    }
+
+    #[test]
+    fn test_alert_negative() {
+        let path = std::env::temp_dir()
+            .join(format!("payments-negative-{}.csv", std::process::id()));
+        let opts = Options {
+            alert_negative_path: Some(path.to_str().unwrap().to_string()),
+            allow_negative: true,
+            ..opts()
+        };
+        let input = "type,client,tx,amount
+deposit,1,1,20
+withdrawal,1,2,50.5
+deposit,2,3,20
+withdrawal,2,4,15
+dispute,2,3,
+deposit,3,5,20
+withdrawal,3,6,15
+dispute,3,5,
+chargeback,3,5,
+";
+        let output = run_str(&opts, input);
+        let alerts = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked
+1,20,0,20,false
+2,-15,20,5,false
+3,-15,0,-15,true
+"
+        );
+        assert_eq!(
+            alerts,
+            "client,available,held,total,locked
+2,-15,20,5,false
+3,-15,0,-15,true
+"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_deposit() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+deposit,1,1,10
+deposit,2,2,1
+withdrawal,1,3,4
+";
+        let mut output = Vec::new();
+        let report = run(&opts(), input.as_bytes(), &mut output).unwrap();
+        assert_eq!((report.rows, report.rejected), (4, 1));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,6,0,6,false
+2,1,0,1,false
+"
+        );
+    }
+
+    #[test]
+    fn test_dedup_consecutive() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,3
+withdrawal,1,2,3
+deposit,1,3,1
+withdrawal,1,2,3
+";
+        // repeated ids are rejected anyway
+        let report = run(&opts(), input.as_bytes(), io::sink()).unwrap();
+        assert_eq!(report.rejected, 2);
+        assert_eq!(
+            run_str(&opts(), input).lines().nth(1),
+            Some("1,8,0,8,false")
+        );
+        let opts = Options {
+            dedup_consecutive: true,
+            ..opts()
+        };
+        // but the repeat right after the first withdrawal is skipped instead
+        let report = run(&opts, input.as_bytes(), io::sink()).unwrap();
+        assert_eq!(report.rejected, 1);
+        assert_eq!(run_str(&opts, input).lines().nth(1), Some("1,8,0,8,false"));
+
+        // a row that doesn't parse comes in between
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,3
+bogus,1,3,1
+withdrawal,1,2,3
+";
+        let report = run(&opts, input.as_bytes(), io::sink()).unwrap();
+        assert_eq!(report.rejected, 2);
+        assert_eq!(report.rejected_by_kind.get("duplicate_tx_id"), Some(&1));
+    }
+
+    #[cfg(feature = "http-input")]
+    #[test]
+    fn test_url_input() {
+        let url = http::tests::serve_once(
+            "HTTP/1.0 200 OK\r\n\r\ntype,client,tx,amount\ndeposit,1,1,2.5\n",
+        );
+        let mut output = Vec::new();
+        run(&opts(), open_input(&url).unwrap(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,2.5,0,2.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_amount_minor() {
+        let input = "type,client,tx,amount,amount_minor
+deposit,1,1,1.5,20000
+deposit,1,2,1.5,
+withdrawal,1,3,,5000
+dispute,1,1,,
+deposit,1,4,1.23456,20000
+";
+        assert_eq!(
+            run_str(&opts(), input),
+            "client,available,held,total,locked\n1,3,2,5,false\n"
+        );
+
+        // without the column at all
+        let input = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+        assert_eq!(
+            run_str(&opts(), input),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_every() {
+        let input = "type,client,tx,amount
+deposit,1,1,1
+deposit,2,2,2
+withdrawal,1,3,5
+deposit,1,4,1
+deposit,3,5,3
+";
+        let opts = Options {
+            checkpoint_every: Some(2),
+            ..opts()
+        };
+        assert_eq!(
+            run_str(&opts, input),
+            "client,available,held,total,locked
+1,1,0,1,false
+2,2,0,2,false
+client,available,held,total,locked
+1,2,0,2,false
+2,2,0,2,false
+client,available,held,total,locked
+1,2,0,2,false
+2,2,0,2,false
+3,3,0,3,false
+"
+        );
+
+        let validate = Options {
+            command: Command::Validate,
+            ..opts
+        };
+        assert_eq!(run_str(&validate, input), "");
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let input = "type,client,tx,amount
+deposit,3,1,1
+deposit,1,2,2
+deposit,2,3,3
+";
+        let expected = run_str(&super::tests::opts(), input);
+        let opts = Options {
+            external_sort: true,
+            ..opts()
+        };
+        assert_eq!(run_str(&opts, input), expected);
+        // a chunk per client
+        let opts = Options {
+            sort_chunk_len: Some(1),
+            ..opts
+        };
+        assert_eq!(run_str(&opts, input), expected);
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut generated = Vec::new();
+        generate(5, 500, 42, &mut generated).unwrap();
+        let mut again = Vec::new();
+        generate(5, 500, 42, &mut again).unwrap();
+        assert_eq!(generated, again);
+
+        // every row parses, so every one gets a result
+        let opts = Options {
+            emit_results: true,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, &generated[..], &mut output).unwrap();
+        assert_eq!(report.rows, 500);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 500);
+    }
+
+    #[test]
+    fn test_mem_report() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,1
+";
+        let report = run(&opts(), input.as_bytes(), io::sink()).unwrap();
+        assert_eq!(report.memory, None);
+        let opts = Options {
+            mem_report: true,
+            ..opts()
+        };
+        let report = run(&opts, input.as_bytes(), io::sink()).unwrap();
+        assert!(report.memory.unwrap().clients > 0);
+    }
+
+    #[test]
+    fn test_decimals() {
+        let input = "type,client,tx,amount
+deposit,1,1,1
+deposit,2,2,0.5
+";
+        let mut output = Vec::new();
+        run(&opts(), input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,1,0,1,false
+2,0.5,0,0.5,false
+"
+        );
+        let opts = Options {
+            decimals: Some(4),
+            ..opts()
+        };
+        let mut output = Vec::new();
+        run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,0.5000,0.0000,0.5000,false
+"
+        );
+    }
+
+    #[test]
+    fn test_verbose() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,3
+withdrawal,1,3,30
+dispute,1,1,
+deposit,1,4,2
+dispute,1,4,
+";
+        let opts = Options {
+            verbose: true,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,\
+deposit_count,withdrawal_count,active_disputes
+1,7,2,9,false,2,1,1
+"
+        );
+    }
+
+    #[test]
+    fn test_include_rejections() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,30
+deposit,2,3,1
+";
+        let opts = Options {
+            include_rejections: true,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(report.rejected, 1);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,10,0,10,false
+2,1,0,1,false
+tx,client,error
+2,1,insufficient funds
+"
+        );
+    }
+
+    #[test]
+    fn test_timing() {
+        let input = "type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,3
+deposit,2,3,1
+";
+        let report = run(&opts(), input.as_bytes(), io::sink()).unwrap();
+        let line = timing(&report);
+        assert!(line.starts_with("processed 3 rows in "), "{}", line);
+
+        // the throughput is left out rather than divided by zero
+        let report = Report {
+            rows: 3,
+            ..Report::default()
+        };
+        assert_eq!(timing(&report), "processed 3 rows in 0.000s");
+        let report = Report {
+            rows: 3000,
+            elapsed: Duration::from_millis(1500),
+            ..Report::default()
+        };
+        assert_eq!(
+            timing(&report),
+            "processed 3000 rows in 1.500s, 2000 rows/s"
+        );
+    }
+
+    #[test]
+    fn test_strict_amounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,1,2,1.50
+dispute,1,1,
+";
+        let strict = Options {
+            strict_amounts: true,
+            ..opts()
+        };
+        assert_eq!(
+            run_str(&strict, input),
+            "client,available,held,total,locked\n1,0,1.5,1.5,false\n"
+        );
+        assert_eq!(
+            run_str(&opts(), input),
+            "client,available,held,total,locked\n1,1.5,1.5,3,false\n"
+        );
+
+        // only where the amount is used
+        let input = "type,client,tx,amount,amount_minor
+deposit,1,1,1.5,
+deposit,1,2,1.50,20000
+dispute,1,1,1.50,
+";
+        assert_eq!(
+            run_str(&strict, input),
+            "client,available,held,total,locked\n1,2,1.5,3.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_numeric_kinds() {
+        let input = "type,client,tx,amount
+1,1,1,10
+2,1,2,3
+1,1,5,2
+3,1,5,
+4,1,5,
+1,2,3,5
+3,2,3,
+5,2,3,
+7,1,4,1
+deposit,1,6,1
+";
+        let opts = Options {
+            numeric_kinds: true,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(report.rejected, 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,9,0,9,false
+2,0,0,0,true
+"
+        );
+
+        let record = csv::StringRecord::from(vec!["7", "1"]);
+        assert_eq!(
+            name_kind(&record, 0),
+            Err("unknown transaction kind code 7".to_string())
+        );
+        let record = csv::StringRecord::from(vec!["1", "deposit"]);
+        assert!(name_kind(&record, 1).unwrap_err().contains("\"deposit\""));
+        // names are the default
+        let input = "type,client,tx,amount\n1,1,1,10\n";
+        assert_eq!(
+            run(&super::tests::opts(), input.as_bytes(), io::sink())
+                .unwrap()
+                .rejected,
+            1
+        );
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let mut input = Vec::new();
+        generate(20, 3 * MEMORY_CHECK_EVERY, 5, &mut input).unwrap();
+        let input = String::from_utf8(input).unwrap();
+        let opts = Options {
+            max_memory: Some(1),
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert!(report.memory_exceeded);
+        assert_eq!(report.rows, MEMORY_CHECK_EVERY);
+        // the summary of just the rows read before stopping
+        let head: Vec<&str> =
+            input.lines().take(MEMORY_CHECK_EVERY + 1).collect();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            run_str(&super::tests::opts(), &(head.join("\n") + "\n"))
+        );
+
+        let opts = Options {
+            max_memory: Some(usize::MAX),
+            ..super::tests::opts()
+        };
+        let report = run(&opts, input.as_bytes(), io::sink()).unwrap();
+        assert!(!report.memory_exceeded);
+        assert_eq!(report.rows, 3 * MEMORY_CHECK_EVERY);
+    }
+
+    #[test]
+    fn test_json_format() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":1.5}
+{"type":"deposit","client":1,"tx":2,"amount":"2"}
+{"type":"dispute","client":1,"tx":2}
+{"type":"deposit","client":2,"tx":3,"amount":
+{"type":"withdrawal","client":1,"tx":4,"amount":0.5}
+"#;
+        let opts = Options {
+            format: Format::Json,
+            ..opts()
+        };
+        let mut output = Vec::new();
+        let report = run(&opts, input.as_bytes(), &mut output).unwrap();
+        assert_eq!((report.rows, report.rejected), (5, 1));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1,2,3,false\n"
+        );
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let mut input = Vec::new();
+        generate(200, 2000, 3, &mut input).unwrap();
+        let input = String::from_utf8(input).unwrap();
+        // differently seeded client maps iterate in different orders
+        let seeded = |seed| Options {
+            seed: Some(seed),
+            ..opts()
+        };
+        let output = run_str(&seeded(1), &input);
+        assert_eq!(run_str(&seeded(2), &input), output);
+        assert_eq!(run_str(&opts(), &input), output);
+
+        let ids: Vec<u16> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert!(ids.len() > 100);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]), "{:?}", ids);
+    }
+
+    #[test]
+    fn test_summary_output() {
+        let input = "type,client,tx,amount
+deposit,2,1,1.5
+deposit,1,2,2
+";
+        assert_eq!(
+            run_str(&opts(), input),
+            "client,available,held,total,locked
+1,2,0,2,false
+2,1.5,0,1.5,false
+"
+        );
+    }
+}
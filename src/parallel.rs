@@ -0,0 +1,280 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::mem;
+use std::panic;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::process::{ClientSummary, TxProcessor};
+use crate::tx::{ClientID, Tx, TxID};
+
+// transactions handed to a shard at once, so that the channel isn't hit
+// for every one of them
+const BATCH: usize = 1024;
+
+// batches queued per shard before the reader waits for it
+const QUEUED_BATCHES: usize = 4;
+
+fn shard_of(client_id: ClientID, shards: usize) -> usize {
+    usize::from(client_id.as_u16()) % shards
+}
+
+/// What `process_parallel` returns.
+#[derive(Debug, Default, PartialEq)]
+pub struct ParallelSummaries {
+    // sorted by client id
+    pub summaries: Vec<ClientSummary>,
+    // transfers between clients of different shards, in input order
+    pub skipped_transfers: Vec<Tx>,
+    // deposits, withdrawals and transfers skipped because a client of
+    // another shard used their id before, in input order
+    pub reused_tx_ids: Vec<Tx>,
+}
+
+/// Processes `txs` on `shards` threads and returns the client summaries
+/// sorted by client id, like `summarize`. Each thread has its own processor
+/// made by `new_processor`, for the clients whose id modulo `shards` is its
+/// index, and gets their transactions in input order, so disputes stay
+/// ordered within a client. Transactions that fail to process are skipped.
+///
+/// Clients never interact, so the result is the sequential one, with two
+/// exceptions:
+/// - a transfer between clients of different shards can't be applied
+///   atomically; it is skipped and returned in `skipped_transfers`
+/// - a shard only knows the ids of its own clients' transactions, so ids
+///   are also checked as they are read: a deposit, withdrawal or transfer
+///   with an id that a client of another shard used before is skipped and
+///   returned in `reused_tx_ids`. Unlike sequentially, it is skipped even
+///   if the first use of the id failed to process.
+pub fn process_parallel<F>(
+    txs: impl IntoIterator<Item = Tx>,
+    shards: usize,
+    new_processor: F,
+) -> ParallelSummaries
+where
+    F: Fn() -> TxProcessor + Sync,
+{
+    let shards = shards.max(1);
+    let new_processor = &new_processor;
+    thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = (0..shards)
+            .map(|_| {
+                let (sender, receiver) =
+                    mpsc::sync_channel::<Vec<Tx>>(QUEUED_BATCHES);
+                let worker = scope.spawn(move || {
+                    let mut tx_proc = new_processor();
+                    for batch in receiver {
+                        for tx in &batch {
+                            let _ = tx_proc.process(tx);
+                        }
+                    }
+                    // inconsistent clients are left out, like by
+                    // `client_summaries`
+                    tx_proc
+                        .client_summaries_unsorted()
+                        .filter_map(Result::ok)
+                        .collect::<Vec<_>>()
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        let mut skipped_transfers = Vec::new();
+        let mut reused_tx_ids = Vec::new();
+        // the shard of the first use of every id
+        let mut id_shards: HashMap<TxID, usize> = HashMap::new();
+        let mut batches = vec![Vec::with_capacity(BATCH); shards];
+        for tx in txs {
+            let shard = shard_of(tx.client_id(), shards);
+            if let Tx::Transfer { to, .. } = tx {
+                if shard_of(to, shards) != shard {
+                    skipped_transfers.push(tx);
+                    continue;
+                }
+            }
+            if let Tx::Deposit { tx_id, .. }
+            | Tx::Withdrawal { tx_id, .. }
+            | Tx::Transfer { tx_id, .. } = tx
+            {
+                match id_shards.entry(tx_id) {
+                    Entry::Occupied(first) if *first.get() != shard => {
+                        reused_tx_ids.push(tx);
+                        continue;
+                    }
+                    Entry::Occupied(_) => (),
+                    Entry::Vacant(first) => {
+                        first.insert(shard);
+                    }
+                }
+            }
+            batches[shard].push(tx);
+            if batches[shard].len() == BATCH {
+                let batch = mem::replace(
+                    &mut batches[shard],
+                    Vec::with_capacity(BATCH),
+                );
+                // the worker only hangs up by panicking, which `join`
+                // passes on below
+                if senders[shard].send(batch).is_err() {
+                    break;
+                }
+            }
+        }
+        for (sender, batch) in senders.into_iter().zip(batches) {
+            let _ = sender.send(batch);
+        }
+
+        let mut summaries: Vec<ClientSummary> = workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|err| panic::resume_unwind(err))
+            })
+            .collect();
+        summaries.sort_by_key(|summary| summary.id);
+        ParallelSummaries {
+            summaries,
+            skipped_transfers,
+            reused_tx_ids,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{write_summaries, Columns};
+    use crate::gen::{Random, SplitMix64, TxGenerator};
+    use crate::process::summarize;
+    use std::collections::BTreeMap;
+
+    // `txs` interleaved differently, each client's keeping their order
+    fn reshuffle(txs: &[Tx], rng: &mut impl Random) -> Vec<Tx> {
+        let mut by_client: BTreeMap<ClientID, Vec<Tx>> = BTreeMap::new();
+        for tx in txs.iter().rev() {
+            by_client.entry(tx.client_id()).or_default().push(*tx);
+        }
+        let mut queues: Vec<Vec<Tx>> = by_client.into_values().collect();
+        let mut shuffled = Vec::with_capacity(txs.len());
+        while !queues.is_empty() {
+            let i = (rng.next_u64() % queues.len() as u64) as usize;
+            shuffled.extend(queues[i].pop());
+            if queues[i].is_empty() {
+                queues.swap_remove(i);
+            }
+        }
+        shuffled
+    }
+
+    #[test]
+    fn test_parallel_is_sequential() {
+        let max_amount = "1000".parse().unwrap();
+        let mut rng = SplitMix64::new(11);
+        let txs: Vec<Tx> = TxGenerator::new(&mut rng, 50, max_amount)
+            .take(20_000)
+            .collect();
+        let sequential = summarize(&txs);
+        let shuffled = reshuffle(&txs, &mut rng);
+        assert_ne!(shuffled, txs);
+        for shards in [1, 3, 8] {
+            let parallel = process_parallel(
+                shuffled.iter().copied(),
+                shards,
+                TxProcessor::new,
+            );
+            assert_eq!(parallel.summaries, sequential, "{} shards", shards);
+            assert!(parallel.skipped_transfers.is_empty());
+            assert!(parallel.reused_tx_ids.is_empty());
+        }
+        assert_eq!(
+            process_parallel(Vec::new(), 4, TxProcessor::new),
+            ParallelSummaries::default()
+        );
+    }
+
+    #[test]
+    fn test_parallel_csv() {
+        let max_amount = "1000".parse().unwrap();
+        let txs: Vec<Tx> =
+            TxGenerator::new(SplitMix64::new(5), 300, max_amount)
+                .take(5_000)
+                .collect();
+        let csv = |summaries: Vec<ClientSummary>| {
+            let mut output = Vec::new();
+            let summaries = summaries.into_iter().map(Ok);
+            write_summaries(&mut output, summaries, Columns::default())
+                .unwrap();
+            output
+        };
+        let sequential = csv(summarize(&txs));
+        for shards in [2, 7] {
+            let parallel =
+                process_parallel(txs.iter().copied(), shards, TxProcessor::new);
+            assert_eq!(csv(parallel.summaries), sequential);
+        }
+    }
+
+    #[test]
+    fn test_parallel_transfers() {
+        let transfer = |from: u16, to: u16, tx_id: u64| Tx::Transfer {
+            from: from.into(),
+            to: to.into(),
+            tx_id: tx_id.into(),
+            amount: "1".parse().unwrap(),
+        };
+        let deposit = |client_id: u16, tx_id: u64| Tx::Deposit {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+            amount: "5".parse().unwrap(),
+        };
+        let txs = vec![
+            deposit(1, 1),
+            deposit(2, 2),
+            transfer(1, 3, 3),
+            transfer(2, 3, 4),
+        ];
+        let parallel = process_parallel(txs, 2, TxProcessor::new);
+        let available: Vec<String> = parallel
+            .summaries
+            .iter()
+            .map(|s| format!("{}:{}", s.id, s.available))
+            .collect();
+        // only the transfer within the odd shard went through
+        assert_eq!(available, ["1:4", "2:5", "3:1"]);
+        assert_eq!(parallel.skipped_transfers, [transfer(2, 3, 4)]);
+    }
+
+    #[test]
+    fn test_parallel_reused_tx_ids() {
+        let deposit = |client_id: u16, tx_id: u64| Tx::Deposit {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+            amount: "5".parse().unwrap(),
+        };
+        let withdrawal = |client_id: u16, tx_id: u64| Tx::Withdrawal {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+            amount: "10".parse().unwrap(),
+        };
+        let available = |summaries: &[ClientSummary]| {
+            let available = summaries
+                .iter()
+                .map(|s| format!("{}:{}", s.id, s.available));
+            available.collect::<Vec<_>>()
+        };
+
+        // an id reused within a shard is the shard's duplicate to reject
+        let txs = [deposit(1, 1), deposit(2, 1), deposit(3, 1)];
+        let parallel = process_parallel(txs, 2, TxProcessor::new);
+        assert_eq!(parallel.summaries, summarize(&txs));
+        assert_eq!(available(&parallel.summaries), ["1:5"]);
+        assert_eq!(parallel.reused_tx_ids, [deposit(2, 1)]);
+
+        // the withdrawal fails, so sequentially the id is still unused
+        let txs = [withdrawal(1, 1), deposit(2, 1)];
+        assert_eq!(available(&summarize(&txs)), ["1:0", "2:5"]);
+        let parallel = process_parallel(txs, 2, TxProcessor::new);
+        assert_eq!(available(&parallel.summaries), ["1:0"]);
+        assert_eq!(parallel.reused_tx_ids, [deposit(2, 1)]);
+    }
+}
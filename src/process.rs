@@ -1,231 +1,3295 @@
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{self, Display};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem::size_of;
 
 use crate::amount::*;
+use crate::json;
 use crate::tx::*;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum TxProcessingError {
     AmountOverflow,
     InsufficientFunds,
+    // a disputed deposit's funds were spent since, unless
+    // `allow_negative_available`
+    CannotHoldInsufficientAvailable,
     DepositNotFound,
     TxAlreadyDisputed,
+    // disputed before and resolved, with `forbid_redispute`
+    AlreadyResolved,
     TxNotDisputed,
     AccountLocked,
+    BelowMinimumBalance,
+    InvariantViolation,
+    DuplicateTxId,
+    ExcessiveDisputes,
+    WrongClientForTx,
+    ReservedClientId,
+    // a deposit or withdrawal of 0, with `reject_zero_amounts`
+    ZeroAmount,
+    // by the validator given to the builder
+    Rejected(String),
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LockReason {
+    None,
+    Chargeback(TxID),
+    AdminFreeze,
+}
+
+// which way a disputed transaction moved the client's funds
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Direction {
+    // a deposit
+    Credit,
+    // a withdrawal
+    Debit,
+}
+
+// a recorded deposit or withdrawal
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct LedgerEntry {
+    client_id: ClientID,
+    amount: Amount,
+}
+
+// keyed by tx id alone: ids are unique across all clients, so that
+// `disputed` and the like need no client either
+type Ledger = HashMap<TxID, LedgerEntry>;
+
 pub struct TxProcessor {
-    clients: HashMap<ClientID, Client>,
-    deposits: HashMap<ClientID, HashMap<TxID, Amount>>,
+    clients: HashMap<ClientID, Client, HashState>,
+    deposits: Ledger,
+    withdrawals: Ledger,
+    disputed: HashSet<TxID>,
+    // deposits whose last dispute was resolved
+    resolved: HashSet<TxID>,
+    // disputes that ended in a chargeback; only the first one is the
+    // client's lock reason
+    charged_back: HashSet<TxID>,
+    // how much less than their amount disputed deposits hold, see
+    // `Policy::partial_hold_on_dispute`
+    shortfalls: HashMap<TxID, Amount>,
+    // the client of every deposit and withdrawal ever recorded; ids are
+    // unique across both and all clients
+    tx_owners: HashMap<TxID, ClientID>,
+    // per client, what is left of each deposit after withdrawals took from
+    // the oldest first, oldest at the front; the opening balance is the
+    // `None` entry. Only kept with `Policy::fifo`
+    fifo: HashMap<ClientID, VecDeque<(Option<TxID>, Amount)>>,
+    policy: Policy,
+    validator: Option<Validator>,
+    stats: KindStats,
+}
+
+/// Custom rules checked before each transaction, see
+/// `TxProcessorBuilder::validator`.
+pub type Validator = Box<dyn Fn(&Tx, &TxProcessor) -> Result<(), String>>;
+
+pub type KindStats = HashMap<TxKind, Outcomes>;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxStatus {
+    Deposited,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Withdrawn,
+}
+
+/// Where a recorded transaction stands, see `TxProcessor::export_status`.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct TxStatusEntry {
+    pub tx: TxID,
+    pub client: ClientID,
+    pub kind: TxKind,
+    pub amount: Amount,
+    pub status: TxStatus,
+}
+
+/// How processing one transaction went, see `TxProcessor::process_reporting`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Processed {
+    pub result: Result<(), TxProcessingError>,
+    // the transaction added its client, or for a transfer either of them,
+    // which happens even when it is rejected (e.g. for insufficient funds):
+    // the client then shows up in summaries with zero balances
+    pub created_client: bool,
+}
+
+/// A transaction `TxProcessor::process_owned` rejected, handed back along
+/// with why, e.g. for audit records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedTx {
+    pub tx: Tx,
+    pub error: TxProcessingError,
+}
+
+impl Display for RejectedTx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to process {:?}: {}", self.tx, self.error)
+    }
+}
+
+impl Error for RejectedTx {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A client whose balances can't be summarized because they violate the
+/// processor's invariants, see `TxProcessor::checked_client_summaries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InconsistentClient {
+    pub client_id: ClientID,
+}
+
+impl Display for InconsistentClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: {}",
+            self.client_id,
+            TxProcessingError::InvariantViolation
+        )
+    }
+}
+
+impl Error for InconsistentClient {}
+
+/// Estimated bytes taken by the entries of the processor's collections:
+/// their counts times the sizes of keys and values. Spare capacity, hash
+/// table control bytes and allocator overhead aren't counted.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryReport {
+    pub clients: usize,
+    // deposits and withdrawals kept for disputes
+    pub ledger: usize,
+    // the owner of every id, for telling reused ids
+    pub tx_owners: usize,
+    // ids of disputed and resolved transactions
+    pub disputes: usize,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> usize {
+        self.clients + self.ledger + self.tx_owners + self.disputes
+    }
+}
+
+impl Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "estimated memory: {} bytes (clients {}, ledger {}, tx owners {}, \
+             disputes {})",
+            self.total(),
+            self.clients,
+            self.ledger,
+            self.tx_owners,
+            self.disputes
+        )
+    }
+}
+
+/// How processing transactions of one kind went.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Outcomes {
+    pub applied: u64,
+    pub rejected: HashMap<TxProcessingError, u64>,
+}
+
+impl Outcomes {
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+}
+
+// configurable rules, defaults keep the plain behavior
+#[derive(Debug, Default)]
+struct Policy {
+    // withdrawals can't take available below this
+    min_balance: Amount,
+    // reject disputes past this many per client over its lifetime
+    max_disputes_per_client: Option<u64>,
+    // kept free of real transactions, e.g. for a synthetic row in reports
+    reserved_client_id: Option<ClientID>,
+    // withdrawals use up the oldest funds first, and only deposits none of
+    // which has been withdrawn that way can be disputed
+    fifo: bool,
+    // disputes take available below zero when the funds were already
+    // withdrawn, instead of failing with insufficient funds
+    allow_negative_available: bool,
+    // a deposit repeating the id of one of the client's deposits adds to
+    // it instead of being rejected as a duplicate
+    coalesce_duplicate_deposits: bool,
+    // a dispute of a deposit exceeding available holds what is available
+    // and records the rest as a shortfall instead of failing
+    partial_hold_on_dispute: bool,
+    // a resolved transaction can't be disputed again
+    forbid_redispute: bool,
+    // deposits and withdrawals of 0 are rejected
+    reject_zero_amounts: bool,
+}
+
+/// Hashes with random keys like `RandomState`, or, for reproducing
+/// iteration order while debugging, with keys derived from a fixed seed.
+#[derive(Clone)]
+pub enum HashState {
+    Random(RandomState),
+    Seeded(u64),
+}
+
+impl Default for HashState {
+    fn default() -> Self {
+        Self::Random(RandomState::new())
+    }
+}
+
+impl BuildHasher for HashState {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            Self::Random(state) => state.build_hasher(),
+            Self::Seeded(seed) => {
+                let mut hasher = DefaultHasher::new();
+                hasher.write_u64(*seed);
+                hasher
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TxProcessorBuilder {
+    policy: Policy,
     disputed: HashSet<TxID>,
+    opening_balances: HashMap<ClientID, Amount>,
+    hash_state: HashState,
+    validator: Option<Validator>,
+}
+
+impl TxProcessorBuilder {
+    pub fn min_balance(mut self, amount: Amount) -> Self {
+        self.policy.min_balance = amount;
+        self
+    }
+
+    pub fn max_disputes_per_client(mut self, max: u64) -> Self {
+        self.policy.max_disputes_per_client = Some(max);
+        self
+    }
+
+    pub fn reserve_client_id(mut self, client_id: ClientID) -> Self {
+        self.policy.reserved_client_id = Some(client_id);
+        self
+    }
+
+    pub fn fifo(mut self, fifo: bool) -> Self {
+        self.policy.fifo = fifo;
+        self
+    }
+
+    /// Lets a dispute of a deposit that was already withdrawn go through,
+    /// leaving the client's available balance negative. By default such a
+    /// dispute is rejected with `CannotHoldInsufficientAvailable`, unlike
+    /// a withdrawal exceeding available with `InsufficientFunds`.
+    pub fn allow_negative_available(mut self, allow: bool) -> Self {
+        self.policy.allow_negative_available = allow;
+        self
+    }
+
+    /// For feeds splitting a deposit over several rows with the same id:
+    /// their amounts are summed into one deposit, which disputes then
+    /// refer to as a whole. Otherwise, like any reused id, the repeats are
+    /// rejected with `DuplicateTxId`.
+    pub fn coalesce_duplicate_deposits(mut self, coalesce: bool) -> Self {
+        self.policy.coalesce_duplicate_deposits = coalesce;
+        self
+    }
+
+    /// Lets a dispute of a deposit that was partly withdrawn hold whatever
+    /// is still available; the part that couldn't be held is reported by
+    /// `TxProcessor::dispute_shortfall`. Resolving or charging it back then
+    /// releases only what was held. `allow_negative_available` takes
+    /// precedence, holding the whole amount.
+    pub fn partial_hold_on_dispute(mut self, partial: bool) -> Self {
+        self.policy.partial_hold_on_dispute = partial;
+        self
+    }
+
+    /// Rejects disputes of transactions whose earlier dispute was resolved
+    /// with `AlreadyResolved`, instead of holding their funds again.
+    /// `dispute_matching` leaves them out.
+    pub fn forbid_redispute(mut self, forbid: bool) -> Self {
+        self.policy.forbid_redispute = forbid;
+        self
+    }
+
+    /// Rejects deposits and withdrawals of 0 with `ZeroAmount` instead of
+    /// recording them, which uses up their ids.
+    pub fn reject_zero_amounts(mut self, reject: bool) -> Self {
+        self.policy.reject_zero_amounts = reject;
+        self
+    }
+
+    /// Runs `validator` on every transaction before applying it, with the
+    /// processor as it is at that point. An `Err` rejects the transaction
+    /// with `TxProcessingError::Rejected`.
+    pub fn validator(
+        mut self,
+        validator: impl Fn(&Tx, &TxProcessor) -> Result<(), String> + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Makes the order clients are kept in depend only on `seed`. Not for
+    /// production: unlike the random default it is open to hash flooding.
+    pub fn hash_seed(mut self, seed: u64) -> Self {
+        self.hash_state = HashState::Seeded(seed);
+        self
+    }
+
+    /// Marks transactions as already disputed, e.g. when rebuilding state
+    /// from a journal that doesn't record disputes. Their deposits are held
+    /// as soon as they get processed.
+    pub fn disputed(mut self, disputed: HashSet<TxID>) -> Self {
+        self.disputed = disputed;
+        self
+    }
+
+    /// Available funds the clients had before this batch.
+    pub fn opening_balances(
+        mut self,
+        opening_balances: HashMap<ClientID, Amount>,
+    ) -> Self {
+        self.opening_balances = opening_balances;
+        self
+    }
+
+    pub fn build(self) -> TxProcessor {
+        let mut clients = HashMap::with_hasher(self.hash_state);
+        clients.extend(self.opening_balances.into_iter().map(
+            |(client_id, amount)| {
+                let client = Client {
+                    available: units(amount),
+                    opening: amount,
+                    ..Client::new()
+                };
+                (client_id, client)
+            },
+        ));
+        let mut fifo = HashMap::new();
+        if self.policy.fifo {
+            for (client_id, client) in &clients {
+                let funds = vec![(None, client.opening)];
+                fifo.insert(*client_id, VecDeque::from(funds));
+            }
+        }
+        TxProcessor {
+            clients,
+            fifo,
+            policy: self.policy,
+            validator: self.validator,
+            disputed: self.disputed,
+            ..TxProcessor::new()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientSummary {
+    pub id: ClientID,
+    pub available: SignedAmount,
+    pub held: Amount,
+    pub total: SignedAmount,
+    pub locked: bool,
+    // balance before this batch, zero unless opening balances were given
+    pub opening: Amount,
+    // balance after this batch, same as total
+    pub closing: SignedAmount,
+    // how many transactions were ever disputed, resolved or not
+    pub disputes: u64,
+    // applied deposits and withdrawals; rejected ones aren't counted
+    pub deposit_count: u64,
+    pub withdrawal_count: u64,
+    // disputes neither resolved nor charged back yet
+    pub active_disputes: u64,
+}
+
+impl Default for TxProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub struct ClientSummary {
-    pub id: ClientID,
-    pub available: Amount,
-    pub held: Amount,
-    pub total: Amount,
-    pub locked: bool,
-}
+impl TxProcessor {
+    pub fn new() -> Self {
+        Self {
+            clients: HashMap::default(),
+            deposits: HashMap::new(),
+            withdrawals: HashMap::new(),
+            disputed: HashSet::new(),
+            resolved: HashSet::new(),
+            charged_back: HashSet::new(),
+            shortfalls: HashMap::new(),
+            tx_owners: HashMap::new(),
+            fifo: HashMap::new(),
+            policy: Policy::default(),
+            validator: None,
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn builder() -> TxProcessorBuilder {
+        TxProcessorBuilder::default()
+    }
+
+    /// Shorthand for `TxProcessor::builder().disputed(disputed).build()`.
+    pub fn with_disputed(disputed: HashSet<TxID>) -> Self {
+        Self::builder().disputed(disputed).build()
+    }
+
+    /// Applies `tx` to its client's balances.
+    ///
+    /// Disputes can refer to deposits and to withdrawals, with opposite
+    /// effects on available:
+    ///
+    /// | referenced | dispute            | resolve        | chargeback     |
+    /// |------------|--------------------|----------------|----------------|
+    /// | deposit    | available -> held  | held -> avail. | held -> gone   |
+    /// | withdrawal | held += amount     | held -= amount | held -> avail. |
+    ///
+    /// So disputing a withdrawal raises held and total by its amount and
+    /// leaves available alone; resolving undoes that, the withdrawal
+    /// standing. Charging it back returns the funds to available. Either
+    /// chargeback locks the account.
+    ///
+    /// A locked account rejects deposits, withdrawals, transfers and
+    /// disputes with `AccountLocked`, but its open disputes can still be
+    /// resolved or charged back to unwind the held funds.
+    ///
+    /// A transfer is a withdrawal from one client and a deposit to another
+    /// that either both happen or, e.g. with `InsufficientFunds` or
+    /// `AmountOverflow`, neither does. It can't be disputed.
+    pub fn process(&mut self, tx: &Tx) -> Result<(), TxProcessingError> {
+        let result = self.apply(tx);
+        if result.is_ok() {
+            if let Some(client) = self.clients.get_mut(&tx.client_id()) {
+                client.chain_hash = chain_hash(client.chain_hash, tx);
+            }
+            if let Tx::Transfer { from, to, .. } = *tx {
+                if let Some(client) =
+                    self.clients.get_mut(&to).filter(|_| from != to)
+                {
+                    client.chain_hash = chain_hash(client.chain_hash, tx);
+                }
+            }
+        }
+        let outcomes = self.stats.entry(tx.kind()).or_default();
+        match result {
+            Ok(()) => outcomes.applied += 1,
+            Err(ref err) => {
+                *outcomes.rejected.entry(err.clone()).or_default() += 1
+            }
+        }
+        result
+    }
+
+    /// `process` taking the transaction by value, for callers that own it
+    /// anyway; a rejected one is handed back with the error. `process`
+    /// itself only reads the fields it needs, so neither relies on `Tx`
+    /// being `Copy`.
+    pub fn process_owned(&mut self, tx: Tx) -> Result<(), RejectedTx> {
+        self.process(&tx).map_err(|error| RejectedTx { tx, error })
+    }
+
+    /// Like `process`, also telling whether `tx` added a client that wasn't
+    /// known before, e.g. for counting new clients.
+    pub fn process_reporting(&mut self, tx: &Tx) -> Processed {
+        let client_ids = match *tx {
+            Tx::Transfer { from, to, .. } => [from, to],
+            _ => [tx.client_id(); 2],
+        };
+        let known = client_ids.map(|id| self.clients.contains_key(&id));
+        let result = self.process(tx);
+        Processed {
+            result,
+            created_client: client_ids
+                .iter()
+                .zip(known)
+                .any(|(id, known)| !known && self.clients.contains_key(id)),
+        }
+    }
+
+    /// Processes `txs` in order, calling `on_before` and `on_after` around
+    /// each of them.
+    pub fn replay_with_hooks<'a>(
+        &mut self,
+        txs: impl IntoIterator<Item = &'a Tx>,
+        mut on_before: impl FnMut(&Tx),
+        mut on_after: impl FnMut(&Tx, &Result<(), TxProcessingError>),
+    ) {
+        for tx in txs {
+            on_before(tx);
+            let result = self.process(tx);
+            on_after(tx, &result);
+        }
+    }
+
+    /// A hash over all transactions applied to the client so far, in order,
+    /// so that two accounts with the same history have the same one.
+    pub fn client_chain_hash(&self, client_id: ClientID) -> Option<u64> {
+        self.clients.get(&client_id).map(|client| client.chain_hash)
+    }
+
+    /// Applied and rejected counts of processed transactions by their kind.
+    pub fn stats_by_kind(&self) -> &KindStats {
+        &self.stats
+    }
+
+    fn apply(&mut self, tx: &Tx) -> Result<(), TxProcessingError> {
+        if let Some(ref validate) = self.validator {
+            validate(tx, self).map_err(TxProcessingError::Rejected)?;
+        }
+        self.check_policy(tx)?;
+        match *tx {
+            Tx::Deposit {
+                client_id,
+                tx_id,
+                amount,
+            } => {
+                let previous = if self.policy.coalesce_duplicate_deposits {
+                    self.deposits
+                        .get(&tx_id)
+                        .filter(|entry| entry.client_id == client_id)
+                        .map(|entry| entry.amount)
+                } else {
+                    None
+                };
+                let recorded = match previous {
+                    Some(previous) => previous
+                        .checked_add(amount)
+                        .ok_or(TxProcessingError::AmountOverflow)?,
+                    None => {
+                        self.check_tx_id_unused(tx_id)?;
+                        amount
+                    }
+                };
+                let disputed = self.disputed.contains(&tx_id);
+                let negative = self.policy.allow_negative_available;
+                let client = self.client(client_id, TxKind::Deposit)?;
+                if disputed {
+                    // credited and held on a copy, so that a hold too big
+                    // for the client leaves it as it was
+                    let mut held = client.clone();
+                    held.deposit(amount)?;
+                    // a coalesced deposit was counted when disputed
+                    let count = if previous.is_some() { 0 } else { 1 };
+                    held.dispute(
+                        Direction::Credit,
+                        amount,
+                        count,
+                        None,
+                        negative,
+                    )?;
+                    *client = held;
+                } else {
+                    client.deposit(amount)?;
+                }
+                let entry = LedgerEntry {
+                    client_id,
+                    amount: recorded,
+                };
+                if self.deposits.insert(tx_id, entry).map(|e| e.amount)
+                    != previous
+                {
+                    panic!("invariant violated: reused id {:?} recorded", tx_id)
+                }
+                if self.policy.fifo {
+                    let funds = self.fifo.entry(client_id).or_default();
+                    let lot = match previous {
+                        Some(_) => {
+                            funds.iter_mut().find(|(id, _)| *id == Some(tx_id))
+                        }
+                        None => None,
+                    };
+                    match lot {
+                        Some((_, left)) => {
+                            *left = left
+                                .checked_add(amount)
+                                .expect("left > recorded deposit")
+                        }
+                        None => funds.push_back((Some(tx_id), amount)),
+                    }
+                }
+                self.record_tx_id(client_id, tx_id);
+                Ok(())
+            }
+            Tx::Withdrawal {
+                client_id,
+                tx_id,
+                amount,
+            } => {
+                self.check_tx_id_unused(tx_id)?;
+                let min_balance = self.policy.min_balance;
+                self.client(client_id, TxKind::Withdrawal)?
+                    .withdraw(amount, min_balance)?;
+                self.withdrawals
+                    .insert(tx_id, LedgerEntry { client_id, amount });
+                if self.policy.fifo {
+                    self.fifo_withdraw(client_id, amount);
+                }
+                self.record_tx_id(client_id, tx_id);
+                Ok(())
+            }
+            // both sides are checked before either changes; the moved
+            // funds are neither a deposit nor a withdrawal, so can't be
+            // disputed
+            Tx::Transfer {
+                from,
+                to,
+                tx_id,
+                amount,
+            } => {
+                self.check_tx_id_unused(tx_id)?;
+                let min_balance = self.policy.min_balance;
+                let available = self
+                    .client(from, TxKind::Transfer)?
+                    .available_after(amount, min_balance)?;
+                if from != to {
+                    self.client(to, TxKind::Transfer)?.credit(amount)?;
+                    self.client(from, TxKind::Transfer)?.available = available;
+                    if self.policy.fifo {
+                        self.fifo_withdraw(from, amount);
+                        let funds = self.fifo.entry(to).or_default();
+                        funds.push_back((None, amount));
+                    }
+                }
+                self.record_tx_id(from, tx_id);
+                Ok(())
+            }
+            Tx::Dispute { client_id, tx_id } => {
+                let (direction, amount) =
+                    self.referenced_entry(client_id, tx_id)?;
+                if self.disputed.contains(&tx_id) {
+                    Err(TxProcessingError::TxAlreadyDisputed)
+                } else if self.redispute_forbidden(tx_id) {
+                    Err(TxProcessingError::AlreadyResolved)
+                } else {
+                    if direction == Direction::Credit {
+                        let entry = [(tx_id, amount)];
+                        self.check_fifo_untouched(client_id, &entry)?;
+                    }
+                    let max = self.policy.max_disputes_per_client;
+                    let negative = self.policy.allow_negative_available;
+                    let partial = self.policy.partial_hold_on_dispute
+                        && !negative
+                        && direction == Direction::Credit;
+                    let client = self.client(client_id, TxKind::Dispute)?;
+                    let hold = if partial {
+                        let available = client
+                            .available()
+                            .and_then(SignedAmount::to_amount);
+                        amount.min(available.unwrap_or_default())
+                    } else {
+                        amount
+                    };
+                    client.dispute(direction, hold, 1, max, negative)?;
+                    if hold != amount {
+                        let shortfall =
+                            amount.checked_sub(hold).expect("hold <= amount");
+                        self.shortfalls.insert(tx_id, shortfall);
+                    }
+                    self.disputed.insert(tx_id);
+                    self.resolved.remove(&tx_id);
+                    Ok(())
+                }
+            }
+            // an unknown tx is reported before one that isn't disputed
+            Tx::Resolve { client_id, tx_id } => {
+                let (direction, amount) =
+                    self.referenced_entry(client_id, tx_id)?;
+                if self.disputed.contains(&tx_id) {
+                    let held = self.held_for_dispute(tx_id, amount);
+                    self.client(client_id, TxKind::Resolve)?
+                        .resolve(direction, held)?;
+                    self.shortfalls.remove(&tx_id);
+                    self.disputed.remove(&tx_id);
+                    self.resolved.insert(tx_id);
+                    Ok(())
+                } else {
+                    Err(TxProcessingError::TxNotDisputed)
+                }
+            }
+            Tx::Chargeback { client_id, tx_id } => {
+                let (direction, amount) =
+                    self.referenced_entry(client_id, tx_id)?;
+                if self.disputed.contains(&tx_id) {
+                    let held = self.held_for_dispute(tx_id, amount);
+                    self.client(client_id, TxKind::Chargeback)?
+                        .chargeback(direction, tx_id, held)?;
+                    self.shortfalls.remove(&tx_id);
+                    self.disputed.remove(&tx_id);
+                    self.charged_back.insert(tx_id);
+                    if let Some(funds) = self.fifo.get_mut(&client_id) {
+                        funds.retain(|(id, _)| *id != Some(tx_id));
+                    }
+                    Ok(())
+                } else {
+                    Err(TxProcessingError::TxNotDisputed)
+                }
+            }
+        }
+    }
+
+    /// Disputes all not yet disputed deposits of the client matching `pred`
+    /// at once, returning how many of them were disputed. If holding their
+    /// total fails, none of them is disputed.
+    pub fn dispute_matching(
+        &mut self,
+        client_id: ClientID,
+        pred: impl Fn(TxID, Amount) -> bool,
+    ) -> Result<usize, TxProcessingError> {
+        let matching: Vec<(TxID, Amount)> = self
+            .client_deposits(client_id)
+            .filter(|(tx_id, amount)| {
+                !self.disputed.contains(tx_id)
+                    && !self.redispute_forbidden(*tx_id)
+                    && pred(*tx_id, *amount)
+            })
+            .collect();
+        if matching.is_empty() {
+            return Ok(0);
+        }
+
+        self.check_fifo_untouched(client_id, &matching)?;
+        // holding the sum in one step keeps the sweep all-or-nothing
+        let amount = matching
+            .iter()
+            .try_fold(Amount::new(), |acc, (_, amount)| {
+                acc.checked_add(*amount)
+            })
+            .ok_or(TxProcessingError::AmountOverflow)?;
+        let max = self.policy.max_disputes_per_client;
+        let negative = self.policy.allow_negative_available;
+        self.client(client_id, TxKind::Dispute)?.dispute(
+            Direction::Credit,
+            amount,
+            matching.len() as u64,
+            max,
+            negative,
+        )?;
+        for (tx_id, _) in &matching {
+            self.disputed.insert(*tx_id);
+            self.resolved.remove(tx_id);
+        }
+        Ok(matching.len())
+    }
+
+    /// Summaries are yielded sorted by client id, so the output doesn't
+    /// depend on `HashMap` iteration order. A client with inconsistent
+    /// balances is left out; `checked_client_summaries` reports it.
+    pub fn client_summaries<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = ClientSummary> + 'a {
+        let mut clients: Vec<(&ClientID, &Client)> =
+            self.clients.iter().collect();
+        clients.sort_unstable_by_key(|(client_id, _)| **client_id);
+        clients
+            .into_iter()
+            .filter_map(|(client_id, client)| client.summary(*client_id))
+    }
+
+    /// Like `client_summaries`, but a client with inconsistent balances
+    /// yields an error in its place, so that the caller can report it and
+    /// a bug in one account doesn't stop the others from being written.
+    pub fn checked_client_summaries(
+        &self,
+    ) -> impl Iterator<Item = Result<ClientSummary, InconsistentClient>> + '_
+    {
+        let mut clients: Vec<(&ClientID, &Client)> =
+            self.clients.iter().collect();
+        clients.sort_unstable_by_key(|(client_id, _)| **client_id);
+        clients.into_iter().map(|(client_id, client)| {
+            client.summary(*client_id).ok_or(InconsistentClient {
+                client_id: *client_id,
+            })
+        })
+    }
+
+    /// Up to `limit` summaries, sorted by client id, of the clients with ids
+    /// after `after`, for paging through them: pass the last id of a page
+    /// to get the next one. Only the clients after `after` get sorted.
+    pub fn client_summaries_page(
+        &self,
+        after: Option<ClientID>,
+        limit: usize,
+    ) -> Vec<ClientSummary> {
+        // `None` sorts before every id
+        let mut clients: Vec<(&ClientID, &Client)> = self
+            .clients
+            .iter()
+            .filter(|(client_id, _)| Some(**client_id) > after)
+            .collect();
+        if limit < clients.len() {
+            clients.select_nth_unstable_by_key(limit, |(id, _)| **id);
+            clients.truncate(limit);
+        }
+        clients.sort_unstable_by_key(|(client_id, _)| **client_id);
+        clients
+            .into_iter()
+            .filter_map(|(client_id, client)| client.summary(*client_id))
+            .collect()
+    }
+
+    /// Like `checked_client_summaries`, but in no particular order, without
+    /// collecting the clients first.
+    pub fn client_summaries_unsorted(
+        &self,
+    ) -> impl Iterator<Item = Result<ClientSummary, InconsistentClient>> + '_
+    {
+        self.clients.iter().map(|(client_id, client)| {
+            client.summary(*client_id).ok_or(InconsistentClient {
+                client_id: *client_id,
+            })
+        })
+    }
+
+    /// The summary of one client, looked up directly; `None` if no
+    /// transaction ever named it, or if its balances are inconsistent.
+    pub fn client_summary(&self, client_id: ClientID) -> Option<ClientSummary> {
+        self.clients
+            .get(&client_id)
+            .and_then(|client| client.summary(client_id))
+    }
+
+    /// Deposits recorded for the client, in no particular order.
+    pub fn client_deposits(
+        &self,
+        client_id: ClientID,
+    ) -> impl Iterator<Item = (TxID, Amount)> + '_ {
+        Self::ledger(&self.deposits, client_id)
+    }
+
+    /// Successful withdrawals of the client, in no particular order.
+    pub fn client_withdrawals(
+        &self,
+        client_id: ClientID,
+    ) -> impl Iterator<Item = (TxID, Amount)> + '_ {
+        Self::ledger(&self.withdrawals, client_id)
+    }
+
+    /// Every recorded deposit and successful withdrawal of all clients, in
+    /// no particular order.
+    pub fn tx_ledger(
+        &self,
+    ) -> impl Iterator<Item = (TxID, ClientID, TxKind)> + '_ {
+        Self::ledger_entries(&self.deposits, TxKind::Deposit)
+            .chain(Self::ledger_entries(&self.withdrawals, TxKind::Withdrawal))
+    }
+
+    /// Status of every recorded deposit and successful withdrawal, sorted by
+    /// tx id. A read-only view for tooling.
+    pub fn export_status(&self) -> Vec<TxStatusEntry> {
+        let ledgers = [
+            (&self.deposits, TxKind::Deposit),
+            (&self.withdrawals, TxKind::Withdrawal),
+        ];
+        let mut status = Vec::with_capacity(self.retained_txs());
+        for (ledger, kind) in ledgers {
+            status.extend(ledger.iter().map(|(tx_id, entry)| TxStatusEntry {
+                tx: *tx_id,
+                client: entry.client_id,
+                kind,
+                amount: entry.amount,
+                status: self.tx_status(*tx_id, kind),
+            }))
+        }
+        status.sort_unstable_by_key(|entry| (entry.tx, entry.kind as u8));
+        status
+    }
+
+    /// `export_status` as a JSON array of objects.
+    pub fn export_status_json(&self) -> String {
+        let objects: Vec<String> = self
+            .export_status()
+            .iter()
+            .map(|entry| {
+                json::Object::new()
+                    .num("tx", entry.tx)
+                    .num("client", entry.client)
+                    .str("kind", &entry.kind.to_string())
+                    .str("amount", &entry.amount.to_string())
+                    .str("status", &entry.status.to_string())
+                    .finish()
+            })
+            .collect();
+        format!("[{}]", objects.join(","))
+    }
+
+    fn tx_status(&self, tx_id: TxID, kind: TxKind) -> TxStatus {
+        if self.disputed.contains(&tx_id) {
+            TxStatus::Disputed
+        } else if self.charged_back.contains(&tx_id) {
+            TxStatus::ChargedBack
+        } else if self.resolved.contains(&tx_id) {
+            TxStatus::Resolved
+        } else if kind == TxKind::Withdrawal {
+            TxStatus::Withdrawn
+        } else {
+            TxStatus::Deposited
+        }
+    }
+
+    fn ledger_entries(
+        ledger: &Ledger,
+        kind: TxKind,
+    ) -> impl Iterator<Item = (TxID, ClientID, TxKind)> + '_ {
+        ledger
+            .iter()
+            .map(move |(tx_id, entry)| (*tx_id, entry.client_id, kind))
+    }
+
+    // a scan of all clients' entries
+    fn ledger(
+        ledger: &Ledger,
+        client_id: ClientID,
+    ) -> impl Iterator<Item = (TxID, Amount)> + '_ {
+        ledger
+            .iter()
+            .filter(move |(_, entry)| entry.client_id == client_id)
+            .map(|(tx_id, entry)| (*tx_id, entry.amount))
+    }
+
+    /// Checks that what all clients hold adds up to the amounts of all
+    /// disputed deposits and withdrawals, which fails with
+    /// `InvariantViolation` on an accounting bug, e.g. a dispute holding
+    /// funds without being recorded. Fails with `AmountOverflow` if the
+    /// sums don't fit in an `Amount`.
+    pub fn verify_held_consistency(&self) -> Result<(), TxProcessingError> {
+        let disputed = self
+            .deposits
+            .iter()
+            .chain(&self.withdrawals)
+            .filter(|(tx_id, _)| self.disputed.contains(tx_id))
+            .try_fold(Amount::new(), |acc, (tx_id, entry)| {
+                acc.checked_add(self.held_for_dispute(*tx_id, entry.amount))
+            });
+        let held = self
+            .clients
+            .values()
+            .try_fold(Amount::new(), |acc, client| {
+                acc.checked_add(client.held()?)
+            });
+        match (disputed, held) {
+            (Some(disputed), Some(held)) if disputed == held => Ok(()),
+            (None, None) => Err(TxProcessingError::AmountOverflow),
+            _ => Err(TxProcessingError::InvariantViolation),
+        }
+    }
+
+    /// How much of the disputed deposit `tx_id` couldn't be held because
+    /// it exceeded available, with `partial_hold_on_dispute`. `None` unless
+    /// it is disputed and something is missing.
+    pub fn dispute_shortfall(&self, tx_id: TxID) -> Option<Amount> {
+        self.shortfalls.get(&tx_id).copied()
+    }
+
+    // what the dispute of a transaction of `amount` actually holds
+    fn held_for_dispute(&self, tx_id: TxID, amount: Amount) -> Amount {
+        match self.shortfalls.get(&tx_id) {
+            Some(shortfall) => {
+                amount.checked_sub(*shortfall).expect("shortfall <= amount")
+            }
+            None => amount,
+        }
+    }
+
+    /// How many deposits and withdrawals are kept around for disputes,
+    /// which is most of what grows with the input; see `compact`.
+    pub fn retained_txs(&self) -> usize {
+        self.deposits.len() + self.withdrawals.len()
+    }
+
+    /// Estimates how much memory the processor's state takes, e.g. for
+    /// sizing machines for a given input.
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            clients: self.clients.len() * size_of::<(ClientID, Client)>(),
+            ledger: self.retained_txs() * size_of::<(TxID, LedgerEntry)>(),
+            tx_owners: self.tx_owners.len() * size_of::<(TxID, ClientID)>(),
+            disputes: (self.disputed.len()
+                + self.resolved.len()
+                + self.charged_back.len())
+                * size_of::<TxID>()
+                + self.shortfalls.len() * size_of::<(TxID, Amount)>(),
+        }
+    }
+
+    /// Forgets the deposits and withdrawals with ids below `keep_from`
+    /// that aren't currently disputed to reclaim memory. They can't be
+    /// disputed afterwards, but their ids stay taken; disputed ones can
+    /// still be resolved or charged back.
+    pub fn compact(&mut self, keep_from: TxID) {
+        let disputed = &self.disputed;
+        let keep = |tx_id: &TxID, _: &mut LedgerEntry| {
+            *tx_id >= keep_from || disputed.contains(tx_id)
+        };
+        self.deposits.retain(keep);
+        self.deposits.shrink_to_fit();
+        self.withdrawals.retain(keep);
+        self.withdrawals.shrink_to_fit();
+    }
+
+    /// Checks whether a withdrawal of `amount` would succeed right now,
+    /// without applying it.
+    pub fn can_withdraw(
+        &self,
+        client_id: ClientID,
+        amount: Amount,
+    ) -> Result<(), TxProcessingError> {
+        // the policy doesn't look at the id
+        self.check_policy(&Tx::Withdrawal {
+            client_id,
+            tx_id: 0.into(),
+            amount,
+        })?;
+        match self.clients.get(&client_id) {
+            Some(client) if client.locked() => {
+                Err(TxProcessingError::AccountLocked)
+            }
+            Some(client) => client
+                .available_after(amount, self.policy.min_balance)
+                .map(|_| ()),
+            None => Client::new()
+                .available_after(amount, self.policy.min_balance)
+                .map(|_| ()),
+        }
+    }
+
+    /// Locks the account administratively; an account already locked by a
+    /// chargeback keeps that as its reason.
+    pub fn freeze(&mut self, client_id: ClientID) {
+        let client = self.clients.entry(client_id).or_insert(Client::new());
+        if !client.locked() {
+            client.lock_reason = LockReason::AdminFreeze
+        }
+    }
+
+    pub fn lock_reason(&self, client_id: ClientID) -> LockReason {
+        self.clients
+            .get(&client_id)
+            .map_or(LockReason::None, |client| client.lock_reason)
+    }
+
+    // takes `amount` from the oldest funds that aren't held by a dispute
+    fn fifo_withdraw(&mut self, client_id: ClientID, mut amount: Amount) {
+        let disputed = &self.disputed;
+        let funds = self.fifo.entry(client_id).or_default();
+        for (tx_id, left) in funds.iter_mut() {
+            if tx_id.is_some_and(|tx_id| disputed.contains(&tx_id)) {
+                continue;
+            }
+            match amount.checked_sub(*left) {
+                Some(rest) => {
+                    amount = rest;
+                    *left = Amount::new();
+                }
+                None => {
+                    *left = left.checked_sub(amount).expect("amount < left");
+                    break;
+                }
+            }
+        }
+        funds.retain(|(_, left)| !left.is_zero());
+    }
+
+    fn check_fifo_untouched(
+        &self,
+        client_id: ClientID,
+        deposits: &[(TxID, Amount)],
+    ) -> Result<(), TxProcessingError> {
+        if !self.policy.fifo {
+            return Ok(());
+        }
+        let funds = self.fifo.get(&client_id);
+        let untouched = |(tx_id, amount): &(TxID, Amount)| {
+            funds
+                .into_iter()
+                .flatten()
+                .any(|(id, left)| *id == Some(*tx_id) && left == amount)
+        };
+        if deposits.iter().all(untouched) {
+            Ok(())
+        } else {
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        }
+    }
+
+    // the policy's checks of any transaction, before those of its kind
+    fn check_policy(&self, tx: &Tx) -> Result<(), TxProcessingError> {
+        let reserved = self.policy.reserved_client_id;
+        if reserved == Some(tx.client_id())
+            || matches!(*tx, Tx::Transfer { to, .. } if reserved == Some(to))
+        {
+            return Err(TxProcessingError::ReservedClientId);
+        }
+        if let Tx::Deposit { amount, .. }
+        | Tx::Withdrawal { amount, .. }
+        | Tx::Transfer { amount, .. } = *tx
+        {
+            if self.policy.reject_zero_amounts && amount.is_zero() {
+                return Err(TxProcessingError::ZeroAmount);
+            }
+        }
+        Ok(())
+    }
+
+    // resolved before, with `forbid_redispute`
+    fn redispute_forbidden(&self, tx_id: TxID) -> bool {
+        self.policy.forbid_redispute && self.resolved.contains(&tx_id)
+    }
+
+    // deposits and withdrawals share one id space across all clients
+    fn check_tx_id_unused(&self, tx_id: TxID) -> Result<(), TxProcessingError> {
+        if self.tx_owners.contains_key(&tx_id) {
+            Err(TxProcessingError::DuplicateTxId)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn record_tx_id(&mut self, client_id: ClientID, tx_id: TxID) {
+        self.tx_owners.insert(tx_id, client_id);
+    }
+
+    // the deposit or withdrawal of the client a dispute, resolve or
+    // chargeback refers to
+    fn ledger_entry(
+        &self,
+        client_id: ClientID,
+        tx_id: TxID,
+    ) -> Option<(Direction, Amount)> {
+        let find = |ledger: &Ledger| {
+            ledger
+                .get(&tx_id)
+                .filter(|entry| entry.client_id == client_id)
+                .map(|entry| entry.amount)
+        };
+        find(&self.deposits)
+            .map(|amount| (Direction::Credit, amount))
+            .or_else(|| {
+                find(&self.withdrawals).map(|amount| (Direction::Debit, amount))
+            })
+    }
+
+    fn referenced_entry(
+        &self,
+        client_id: ClientID,
+        tx_id: TxID,
+    ) -> Result<(Direction, Amount), TxProcessingError> {
+        match self.ledger_entry(client_id, tx_id) {
+            Some(entry) => Ok(entry),
+            None => match self.tx_owners.get(&tx_id) {
+                Some(owner) if *owner != client_id => {
+                    Err(TxProcessingError::WrongClientForTx)
+                }
+                _ => Err(TxProcessingError::DepositNotFound),
+            },
+        }
+    }
+
+    // The client to apply a transaction of `kind` to. A locked account
+    // (charged back or frozen) takes no new funds and opens no new holds,
+    // so deposits, withdrawals and disputes are rejected. Resolves and
+    // chargebacks only settle disputes opened before the lock and go
+    // through. Reading its state, e.g. `client_summary`, is never blocked.
+    fn client(
+        &mut self,
+        client_id: ClientID,
+        kind: TxKind,
+    ) -> Result<&mut Client, TxProcessingError> {
+        let client = self.clients.entry(client_id).or_insert(Client::new());
+        let settles = matches!(kind, TxKind::Resolve | TxKind::Chargeback);
+        if client.locked() && !settles {
+            Err(TxProcessingError::AccountLocked)
+        } else {
+            Ok(client)
+        }
+    }
+}
+
+/// Processes `txs` in order from scratch and returns the resulting client
+/// summaries sorted by client id. Transactions that fail to process are
+/// skipped.
+///
+/// ```
+/// use payments::process::summarize;
+/// use payments::tx::Tx;
+///
+/// let csv = "type,client,tx,amount
+/// deposit,2,1,5
+/// deposit,1,2,2.5
+/// withdrawal,1,3,10
+/// ";
+/// let txs: Vec<Tx> = csv::Reader::from_reader(csv.as_bytes())
+///     .deserialize()
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+/// let summaries = summarize(&txs);
+/// assert_eq!(summaries.len(), 2);
+/// assert_eq!(summaries[0].available.to_string(), "2.5");
+/// assert_eq!(summaries[1].available.to_string(), "5");
+/// ```
+pub fn summarize(txs: &[Tx]) -> Vec<ClientSummary> {
+    let mut tx_proc = TxProcessor::new();
+    for tx in txs {
+        // a failed transaction leaves the state as it was
+        let _ = tx_proc.process(tx);
+    }
+    tx_proc.client_summaries().collect()
+}
+
+// the first byte hashed for every transaction, to be bumped whenever the
+// encoding below changes
+const CHAIN_HASH_VERSION: u8 = 1;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a 64 over an explicit encoding, so hashes are comparable across
+// processors, builds and Rust versions: the version, the kind code of
+// `TryFrom<u8> for TxKind`, `prev`, the client id, the credited client id
+// of a transfer, the transaction id and the amount in minor units if the
+// kind has one; integers are little-endian
+fn chain_hash(prev: u64, tx: &Tx) -> u64 {
+    let (to, amount) = match *tx {
+        Tx::Deposit { amount, .. } | Tx::Withdrawal { amount, .. } => {
+            (None, Some(amount))
+        }
+        Tx::Transfer { to, amount, .. } => (Some(to), Some(amount)),
+        Tx::Dispute { .. } | Tx::Resolve { .. } | Tx::Chargeback { .. } => {
+            (None, None)
+        }
+    };
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut write = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+        }
+    };
+    write(&[CHAIN_HASH_VERSION, u8::from(tx.kind())]);
+    write(&prev.to_le_bytes());
+    write(&tx.client_id().as_u16().to_le_bytes());
+    if let Some(to) = to {
+        write(&to.as_u16().to_le_bytes());
+    }
+    write(&tx.tx_id().as_u64().to_le_bytes());
+    if let Some(amount) = amount {
+        write(&amount.to_minor_units().to_le_bytes());
+    }
+    hash
+}
+
+#[derive(Debug, Clone)]
+struct Client {
+    // in minor units, wide enough that sums of `Amount`s can't overflow
+    // them; narrowed back to amounts in `summary`
+    available: i128,
+    held: i128,
+    lock_reason: LockReason,
+    opening: Amount,
+    disputes: u64,
+    deposit_count: u64,
+    withdrawal_count: u64,
+    active_disputes: u64,
+    chain_hash: u64,
+}
+
+fn units(amount: Amount) -> i128 {
+    i128::from(amount.to_minor_units())
+}
+
+// invariant: held >= 0
+// invariant: available, held and their total fit the summary's amounts
+impl Client {
+    fn new() -> Self {
+        Self {
+            available: 0,
+            held: 0,
+            lock_reason: LockReason::None,
+            opening: Amount::new(),
+            disputes: 0,
+            deposit_count: 0,
+            withdrawal_count: 0,
+            active_disputes: 0,
+            chain_hash: 0,
+        }
+    }
+
+    // `None` if the balances don't fit, which the invariant rules out
+    fn summary(&self, id: ClientID) -> Option<ClientSummary> {
+        let total = self.total()?;
+        Some(ClientSummary {
+            id,
+            available: self.available()?,
+            held: self.held()?,
+            total,
+            locked: self.locked(),
+            opening: self.opening,
+            closing: total,
+            disputes: self.disputes,
+            deposit_count: self.deposit_count,
+            withdrawal_count: self.withdrawal_count,
+            active_disputes: self.active_disputes,
+        })
+    }
+
+    fn locked(&self) -> bool {
+        self.lock_reason != LockReason::None
+    }
+
+    fn available(&self) -> Option<SignedAmount> {
+        SignedAmount::from_minor_units(self.available)
+    }
+
+    fn held(&self) -> Option<Amount> {
+        u64::try_from(self.held).ok().map(Amount::from_minor_units)
+    }
+
+    fn total(&self) -> Option<SignedAmount> {
+        SignedAmount::from_minor_units(self.available + self.held)
+    }
+
+    fn fits(available: i128, held: i128) -> bool {
+        SignedAmount::from_minor_units(available).is_some()
+            && u64::try_from(held).is_ok()
+            && SignedAmount::from_minor_units(available + held).is_some()
+    }
+
+    fn deposit(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
+        self.credit(amount)?;
+        self.deposit_count += 1;
+        Ok(())
+    }
+
+    fn credit(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
+        let available = self.available + units(amount);
+        if !Self::fits(available, self.held) {
+            return Err(TxProcessingError::AmountOverflow);
+        }
+        self.available = available;
+        Ok(())
+    }
+
+    fn withdraw(
+        &mut self,
+        amount: Amount,
+        min_balance: Amount,
+    ) -> Result<(), TxProcessingError> {
+        self.available = self.available_after(amount, min_balance)?;
+        self.withdrawal_count += 1;
+        Ok(())
+    }
+
+    fn available_after(
+        &self,
+        withdrawal: Amount,
+        min_balance: Amount,
+    ) -> Result<i128, TxProcessingError> {
+        match self.available - units(withdrawal) {
+            x if x < 0 => Err(TxProcessingError::InsufficientFunds),
+            x if x < units(min_balance) => {
+                Err(TxProcessingError::BelowMinimumBalance)
+            }
+            x => Ok(x),
+        }
+    }
+
+    // `amount` is held for `count` transactions at once. Credits move it
+    // from available, which unless `negative` has to have it; debits hold
+    // the withdrawn funds on top of the total.
+    fn dispute(
+        &mut self,
+        direction: Direction,
+        amount: Amount,
+        count: u64,
+        max_disputes: Option<u64>,
+        negative: bool,
+    ) -> Result<(), TxProcessingError> {
+        if max_disputes.is_some_and(|max| self.disputes + count > max) {
+            return Err(TxProcessingError::ExcessiveDisputes);
+        }
+        let available = match direction {
+            Direction::Credit => self.available - units(amount),
+            Direction::Debit => self.available,
+        };
+        if available < 0 && !negative && direction == Direction::Credit {
+            return Err(TxProcessingError::CannotHoldInsufficientAvailable);
+        }
+        // with available negative held can outgrow total
+        let held = self.held + units(amount);
+        if !Self::fits(available, held) {
+            return Err(TxProcessingError::AmountOverflow);
+        }
+        self.available = available;
+        self.held = held;
+        self.disputes += count;
+        self.active_disputes += count;
+        Ok(())
+    }
+
+    // releasing held funds keeps the total or lowers it, so only the held
+    // amount itself can be wrong
+    fn release(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
+        if self.held < units(amount) {
+            return Err(TxProcessingError::InvariantViolation);
+        }
+        self.held -= units(amount);
+        self.active_disputes -= 1;
+        Ok(())
+    }
+
+    // a resolved debit stays withdrawn
+    fn resolve(
+        &mut self,
+        direction: Direction,
+        amount: Amount,
+    ) -> Result<(), TxProcessingError> {
+        self.release(amount)?;
+        if direction == Direction::Credit {
+            self.available += units(amount);
+        }
+        Ok(())
+    }
+
+    // a charged back debit returns the withdrawn funds to the client
+    fn chargeback(
+        &mut self,
+        direction: Direction,
+        tx_id: TxID,
+        amount: Amount,
+    ) -> Result<(), TxProcessingError> {
+        self.release(amount)?;
+        if direction == Direction::Debit {
+            self.available += units(amount);
+        }
+        // the first reason sticks, like with `freeze`
+        if !self.locked() {
+            self.lock_reason = LockReason::Chargeback(tx_id)
+        }
+        Ok(())
+    }
+}
+
+impl TxProcessingError {
+    /// A stable name of the error for machine-readable reports, e.g.
+    /// `insufficient_funds`, unlike `Display` not meant to be reworded.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Self::AmountOverflow => "amount_overflow",
+            Self::InsufficientFunds => "insufficient_funds",
+            Self::CannotHoldInsufficientAvailable => {
+                "cannot_hold_insufficient_available"
+            }
+            Self::DepositNotFound => "deposit_not_found",
+            Self::TxAlreadyDisputed => "already_disputed",
+            Self::AlreadyResolved => "already_resolved",
+            Self::TxNotDisputed => "not_disputed",
+            Self::AccountLocked => "account_locked",
+            Self::BelowMinimumBalance => "below_minimum_balance",
+            Self::InvariantViolation => "invariant_violation",
+            Self::DuplicateTxId => "duplicate_tx_id",
+            Self::ExcessiveDisputes => "excessive_disputes",
+            Self::WrongClientForTx => "wrong_client",
+            Self::ReservedClientId => "reserved_client_id",
+            Self::ZeroAmount => "zero_amount",
+            Self::Rejected(_) => "rejected",
+        }
+    }
+}
+
+impl Display for TxProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::AmountOverflow => write!(f, "amount overflow"),
+            Self::InsufficientFunds => write!(f, "insufficient funds"),
+            Self::CannotHoldInsufficientAvailable => {
+                write!(f, "not enough available funds to hold for dispute")
+            }
+            Self::DepositNotFound => write!(f, "deposit not found"),
+            Self::TxAlreadyDisputed => {
+                write!(f, "transaction is already disputed")
+            }
+            Self::AlreadyResolved => {
+                write!(f, "transaction was already disputed and resolved")
+            }
+            Self::TxNotDisputed => write!(f, "transaction is not disputed"),
+            Self::AccountLocked => write!(f, "account is locked"),
+            Self::BelowMinimumBalance => {
+                write!(f, "balance would drop below minimum")
+            }
+            Self::InvariantViolation => write!(f, "invariant violated"),
+            Self::DuplicateTxId => write!(f, "duplicate transaction id"),
+            Self::ExcessiveDisputes => write!(f, "too many disputes"),
+            Self::ReservedClientId => write!(f, "client id is reserved"),
+            Self::ZeroAmount => write!(f, "amount is zero"),
+            Self::Rejected(ref reason) => write!(f, "rejected: {}", reason),
+            Self::WrongClientForTx => {
+                write!(f, "transaction belongs to another client")
+            }
+        }
+    }
+}
+impl Error for TxProcessingError {}
+
+impl Display for TxStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Deposited => "deposited",
+            Self::Disputed => "disputed",
+            Self::Resolved => "resolved",
+            Self::ChargedBack => "charged-back",
+            Self::Withdrawn => "withdrawn",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{write_summaries, Columns};
+    use crate::gen::{Random, TxGenerator};
+    use quickcheck::{Arbitrary, Gen};
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    fn deposit(client_id: u16, tx_id: u64, a: &str) -> Tx {
+        Tx::Deposit {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+            amount: amount(a),
+        }
+    }
+
+    fn withdrawal(client_id: u16, tx_id: u64, a: &str) -> Tx {
+        Tx::Withdrawal {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+            amount: amount(a),
+        }
+    }
+
+    fn dispute(client_id: u16, tx_id: u64) -> Tx {
+        Tx::Dispute {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+        }
+    }
+
+    fn resolve(client_id: u16, tx_id: u64) -> Tx {
+        Tx::Resolve {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+        }
+    }
+
+    fn summary(tx_proc: &TxProcessor, client_id: u16) -> ClientSummary {
+        let id = client_id.into();
+        tx_proc.client_summaries().find(|s| s.id == id).unwrap()
+    }
+
+    #[test]
+    fn test_forbid_redispute() {
+        let txs = [
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            dispute(1, 1),
+            resolve(1, 1),
+        ];
+        let mut tx_proc = TxProcessor::new();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
+        }
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).held, amount("10"));
+
+        let mut tx_proc = TxProcessor::builder().forbid_redispute(true).build();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
+        }
+        assert_eq!(
+            tx_proc.process(&dispute(1, 1)),
+            Err(TxProcessingError::AlreadyResolved)
+        );
+        assert_eq!(summary(&tx_proc, 1).held, Amount::ZERO);
+        // never disputed ones still can be, in a sweep too
+        assert_eq!(tx_proc.dispute_matching(1.into(), |_, _| true), Ok(1));
+        assert_eq!(summary(&tx_proc, 1).held, amount("5"));
+    }
+
+    // a chargeback is final under either policy: the lock it leaves keeps
+    // its funds from being held a second time
+    #[test]
+    fn test_no_redispute_after_chargeback() {
+        for forbid in [false, true] {
+            let mut tx_proc =
+                TxProcessor::builder().forbid_redispute(forbid).build();
+            for tx in &[
+                deposit(1, 1, "10"),
+                dispute(1, 1),
+                Tx::Chargeback {
+                    client_id: 1.into(),
+                    tx_id: 1.into(),
+                },
+            ] {
+                tx_proc.process(tx).unwrap();
+            }
+            assert_eq!(
+                tx_proc.process(&dispute(1, 1)),
+                Err(TxProcessingError::AccountLocked)
+            );
+            let s = summary(&tx_proc, 1);
+            assert_eq!((s.total, s.held), (Amount::ZERO.into(), Amount::ZERO));
+        }
+    }
+
+    // the held funds of the other dispute aren't stranded by the lock
+    #[test]
+    fn test_resolve_after_chargeback() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            dispute(1, 1),
+            dispute(1, 2),
+            Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 1.into(),
+            },
+            resolve(1, 2),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.held), (amount("5").into(), Amount::ZERO));
+        assert!(s.locked);
+    }
+
+    #[test]
+    fn test_locked_account() {
+        let chargeback = |client_id: u16, tx_id: u64| Tx::Chargeback {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+        };
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            deposit(1, 3, "1"),
+            deposit(1, 4, "2"),
+            dispute(1, 1),
+            dispute(1, 2),
+            dispute(1, 4),
+            chargeback(1, 1),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        let locked = Err(TxProcessingError::AccountLocked);
+        assert_eq!(tx_proc.process(&deposit(1, 5, "1")), locked);
+        assert_eq!(tx_proc.process(&withdrawal(1, 6, "1")), locked);
+        assert_eq!(tx_proc.process(&dispute(1, 3)), locked);
+        assert_eq!(
+            tx_proc.dispute_matching(1.into(), |_, _| true),
+            Err(TxProcessingError::AccountLocked)
+        );
+        // open disputes can still be settled
+        assert_eq!(tx_proc.process(&resolve(1, 2)), Ok(()));
+        assert_eq!(tx_proc.process(&dispute(1, 2)), locked);
+        assert_eq!(tx_proc.process(&chargeback(1, 4)), Ok(()));
+        assert_eq!(
+            tx_proc.process(&resolve(1, 4)),
+            Err(TxProcessingError::TxNotDisputed)
+        );
+
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.held), (amount("6").into(), Amount::ZERO));
+        assert_eq!((s.total, s.locked), (amount("6").into(), true));
+        assert_eq!(
+            tx_proc.lock_reason(1.into()),
+            LockReason::Chargeback(1.into())
+        );
+        let status: Vec<TxStatus> = tx_proc
+            .export_status()
+            .into_iter()
+            .map(|entry| entry.status)
+            .collect();
+        assert_eq!(
+            status,
+            [
+                TxStatus::ChargedBack,
+                TxStatus::Resolved,
+                TxStatus::Deposited,
+                TxStatus::ChargedBack
+            ]
+        );
+
+        // a frozen account settles its disputes too
+        tx_proc.process(&deposit(2, 7, "3")).unwrap();
+        tx_proc.process(&dispute(2, 7)).unwrap();
+        tx_proc.freeze(2.into());
+        assert_eq!(tx_proc.process(&resolve(2, 7)), Ok(()));
+        assert_eq!(tx_proc.lock_reason(2.into()), LockReason::AdminFreeze);
+        assert_eq!(summary(&tx_proc, 2).available, amount("3"));
+    }
+
+    #[test]
+    fn test_reject_zero_amounts() {
+        let txs = [deposit(1, 1, "0"), withdrawal(1, 2, "0.0000")];
+        let mut tx_proc = TxProcessor::new();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
+        }
+        assert_eq!(tx_proc.retained_txs(), 2);
+
+        let mut tx_proc =
+            TxProcessor::builder().reject_zero_amounts(true).build();
+        for tx in &txs {
+            assert_eq!(tx_proc.process(tx), Err(TxProcessingError::ZeroAmount));
+        }
+        assert_eq!(tx_proc.retained_txs(), 0);
+        // the ids weren't used up
+        tx_proc.process(&deposit(1, 1, "0.0001")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "0.0001")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).total, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_operation_counts() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            withdrawal(1, 3, "3"),
+            dispute(1, 2),
+            resolve(1, 2),
+            dispute(1, 1),
+            deposit(2, 4, "1"),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        // rejected ones are not counted
+        assert!(tx_proc.process(&withdrawal(1, 5, "100")).is_err());
+        assert!(tx_proc.process(&deposit(1, 1, "1")).is_err());
+        assert!(tx_proc.process(&dispute(1, 1)).is_err());
+        assert!(tx_proc.process(&resolve(1, 2)).is_err());
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.deposit_count, 2);
+        assert_eq!(s.withdrawal_count, 1);
+        assert_eq!(s.active_disputes, 1);
+
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 1.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.deposit_count, s.active_disputes), (2, 0));
+        let s = summary(&tx_proc, 2);
+        assert_eq!((s.deposit_count, s.withdrawal_count), (1, 0));
+    }
+
+    #[test]
+    fn test_dispute_matching() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "50.00"),
+            deposit(1, 2, "20"),
+            deposit(1, 3, "50"),
+            deposit(2, 4, "50"),
+            deposit(1, 5, "50.0001"),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        let fifty = amount("50");
+        let n = tx_proc
+            .dispute_matching(1.into(), |_, amount| amount == fifty)
+            .unwrap();
+        assert_eq!(n, 2);
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.held, amount("100"));
+        assert_eq!(s.available, amount("70.0001"));
+        assert_eq!(summary(&tx_proc, 2).held, Amount::new());
+
+        // already disputed deposits are not disputed again
+        let n = tx_proc
+            .dispute_matching(1.into(), |_, amount| amount == fifty)
+            .unwrap();
+        assert_eq!(n, 0);
+        assert_eq!(summary(&tx_proc, 1).held, amount("100"));
+    }
+
+    #[test]
+    fn test_dispute_matching_rolls_back() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "50"),
+            deposit(1, 2, "50"),
+            withdrawal(1, 3, "60"),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        let fifty = amount("50");
+        assert!(matches!(
+            tx_proc.dispute_matching(1.into(), |_, amount| amount == fifty),
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        ));
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.held, Amount::new());
+        assert_eq!(s.available, amount("40"));
+        assert!(matches!(
+            tx_proc.process(&Tx::Resolve {
+                client_id: 1.into(),
+                tx_id: 1.into(),
+            }),
+            Err(TxProcessingError::TxNotDisputed)
+        ));
+    }
+
+    #[test]
+    fn test_client_summaries_order() {
+        let txs = vec![
+            deposit(3, 1, "1"),
+            deposit(1, 2, "2"),
+            withdrawal(3, 3, "0.5"),
+            deposit(2, 4, "3"),
+            deposit(1, 5, "4"),
+        ];
+        // same per-client order, different interleaving across clients
+        let reordered: Vec<Tx> = [1, 2, 3]
+            .iter()
+            .flat_map(|id: &u16| {
+                let id = (*id).into();
+                txs.iter().filter(move |tx| match tx {
+                    Tx::Deposit { client_id, .. }
+                    | Tx::Withdrawal { client_id, .. } => *client_id == id,
+                    _ => false,
+                })
+            })
+            .copied()
+            .collect();
+
+        let summaries = |txs: &[Tx]| {
+            let mut tx_proc = TxProcessor::new();
+            for tx in txs {
+                tx_proc.process(tx).unwrap();
+            }
+            tx_proc.client_summaries().collect::<Vec<_>>()
+        };
+        let a = summaries(&txs);
+        let b = summaries(&reordered);
+        assert_eq!(a, b);
+        let ids: Vec<ClientID> = a.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1.into(), 2.into(), 3.into()]);
+    }
+
+    #[test]
+    fn test_lock_reason() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            deposit(2, 3, "10"),
+            Tx::Dispute {
+                client_id: 1.into(),
+                tx_id: 2.into(),
+            },
+            Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 2.into(),
+            },
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        assert_eq!(
+            tx_proc.lock_reason(1.into()),
+            LockReason::Chargeback(2.into())
+        );
+        assert_eq!(tx_proc.lock_reason(2.into()), LockReason::None);
+        assert_eq!(tx_proc.lock_reason(3.into()), LockReason::None);
+
+        tx_proc.freeze(2.into());
+        assert_eq!(tx_proc.lock_reason(2.into()), LockReason::AdminFreeze);
+        assert!(summary(&tx_proc, 2).locked);
+        assert!(matches!(
+            tx_proc.process(&deposit(2, 4, "1")),
+            Err(TxProcessingError::AccountLocked)
+        ));
+
+        // freezing doesn't hide the chargeback
+        tx_proc.freeze(1.into());
+        assert_eq!(
+            tx_proc.lock_reason(1.into()),
+            LockReason::Chargeback(2.into())
+        );
+    }
+
+    #[test]
+    fn test_min_balance() {
+        let mut tx_proc =
+            TxProcessor::builder().min_balance(amount("10")).build();
+        tx_proc.process(&deposit(1, 1, "30")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "20")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, amount("10"));
+        assert!(matches!(
+            tx_proc.process(&withdrawal(1, 3, "0.0001")),
+            Err(TxProcessingError::BelowMinimumBalance)
+        ));
+        assert!(matches!(
+            tx_proc.process(&withdrawal(1, 4, "11")),
+            Err(TxProcessingError::InsufficientFunds)
+        ));
+        assert_eq!(summary(&tx_proc, 1).available, amount("10"));
+
+        // no floor by default
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, "30")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "30")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, Amount::new());
+    }
+
+    #[test]
+    fn test_can_withdraw() {
+        let mut tx_proc =
+            TxProcessor::builder().min_balance(amount("1")).build();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        assert_eq!(tx_proc.can_withdraw(1.into(), amount("9")), Ok(()));
+        assert_eq!(
+            tx_proc.can_withdraw(1.into(), amount("9.5")),
+            Err(TxProcessingError::BelowMinimumBalance)
+        );
+        assert_eq!(
+            tx_proc.can_withdraw(1.into(), amount("11")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+        assert_eq!(
+            tx_proc.can_withdraw(2.into(), amount("1")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+        // nothing was applied
+        assert_eq!(summary(&tx_proc, 1).available, amount("10"));
+        assert!(tx_proc.client_summary(2.into()).is_none());
+
+        tx_proc.freeze(1.into());
+        assert_eq!(
+            tx_proc.can_withdraw(1.into(), amount("1")),
+            Err(TxProcessingError::AccountLocked)
+        );
 
-impl TxProcessor {
-    pub fn new() -> Self {
-        Self {
-            clients: HashMap::new(),
-            deposits: HashMap::new(),
-            disputed: HashSet::new(),
+        // the same policy checks as processing
+        let mut tx_proc =
+            TxProcessor::builder().reject_zero_amounts(true).build();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        assert_eq!(
+            tx_proc.can_withdraw(1.into(), Amount::ZERO),
+            tx_proc.process(&withdrawal(1, 2, "0"))
+        );
+        assert_eq!(
+            tx_proc.can_withdraw(1.into(), Amount::ZERO),
+            Err(TxProcessingError::ZeroAmount)
+        );
+    }
+
+    #[test]
+    fn test_memory_report() {
+        let report = |clients: u16, deposits: u64| {
+            let mut tx_proc = TxProcessor::new();
+            for tx_id in 0..deposits {
+                let client_id = (tx_id % u64::from(clients)) as u16;
+                tx_proc.process(&deposit(client_id, tx_id, "1")).unwrap();
+            }
+            tx_proc.memory_report()
+        };
+        assert_eq!(TxProcessor::new().memory_report().total(), 0);
+
+        let small = report(10, 100);
+        assert!(small.clients > 0 && small.ledger > 0 && small.tx_owners > 0);
+        assert_eq!(small.disputes, 0);
+        assert_eq!(report(20, 100).clients, 2 * small.clients);
+        let large = report(10, 1000);
+        assert_eq!(large.clients, small.clients);
+        assert_eq!(large.tx_owners, 10 * small.tx_owners);
+        assert_eq!(
+            large.ledger - small.ledger,
+            900 * size_of::<(TxID, LedgerEntry)>()
+        );
+        assert!(large.total() > small.total());
+        assert!(large.to_string().starts_with(&format!(
+            "estimated memory: {} bytes (clients ",
+            large.total()
+        )));
+    }
+
+    #[test]
+    fn test_client_summary() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "2"),
+            dispute(1, 2),
+            deposit(3, 3, "1"),
+            dispute(3, 3),
+        ] {
+            tx_proc.process(tx).unwrap();
         }
+        let s = tx_proc.client_summary(1.into()).unwrap();
+        assert_eq!(s, summary(&tx_proc, 1));
+        assert_eq!((s.available, s.held), (amount("10").into(), amount("2")));
+
+        assert_eq!(tx_proc.client_summary(4.into()), None);
+        // only there because of a rejected withdrawal
+        assert_eq!(
+            tx_proc.process(&withdrawal(2, 4, "1")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+        let s = tx_proc.client_summary(2.into()).unwrap();
+        assert_eq!((s.total, s.locked), (Amount::ZERO.into(), false));
+
+        // a locked client keeps its balances after rejected transactions
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 3.into(),
+                tx_id: 3.into(),
+            })
+            .unwrap();
+        assert_eq!(
+            tx_proc.process(&deposit(3, 5, "1")),
+            Err(TxProcessingError::AccountLocked)
+        );
+        let s = tx_proc.client_summary(3.into()).unwrap();
+        assert_eq!((s.total, s.locked), (Amount::ZERO.into(), true));
     }
 
-    pub fn process(&mut self, tx: &Tx) -> Result<(), TxProcessingError> {
-        match *tx {
-            Tx::Deposit {
-                client_id,
-                tx_id,
-                amount,
-            } => {
-                self.client(client_id)?.deposit(amount)?;
-                if self
-                    .deposits
-                    .entry(client_id)
-                    .or_insert(HashMap::new())
-                    .insert(tx_id, amount)
-                    .is_some()
-                {
-                    panic!("duplicate transaction id {:?}", tx_id)
-                }
-                Ok(())
+    #[test]
+    fn test_settling_error_precedence() {
+        let chargeback = |client_id: u16, tx_id: u64| Tx::Chargeback {
+            client_id: client_id.into(),
+            tx_id: tx_id.into(),
+        };
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&deposit(1, 4, "5")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "1")).unwrap();
+        for settle in &[resolve, chargeback] {
+            for (tx, err) in &[
+                (settle(1, 3), TxProcessingError::DepositNotFound),
+                (settle(1, 1), TxProcessingError::TxNotDisputed),
+                (settle(1, 2), TxProcessingError::TxNotDisputed),
+                (settle(2, 1), TxProcessingError::WrongClientForTx),
+            ] {
+                assert_eq!(tx_proc.process(tx).as_ref(), Err(err), "{:?}", tx);
             }
+        }
+        // a settled dispute is no longer disputed; a compacted deposit is
+        // gone altogether
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        tx_proc.process(&resolve(1, 1)).unwrap();
+        assert_eq!(
+            tx_proc.process(&resolve(1, 1)),
+            Err(TxProcessingError::TxNotDisputed)
+        );
+        tx_proc.compact(u64::MAX.into());
+        assert_eq!(
+            tx_proc.process(&chargeback(1, 1)),
+            Err(TxProcessingError::DepositNotFound)
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("14"));
+    }
+
+    #[test]
+    fn test_process_owned() {
+        let txs = [
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "3"),
+            withdrawal(1, 3, "30"),
+            dispute(1, 1),
+            deposit(2, 4, "1"),
+            resolve(1, 1),
+            dispute(2, 4),
+        ];
+        let mut borrowing = TxProcessor::new();
+        let borrowed: Vec<_> =
+            txs.iter().map(|tx| borrowing.process(tx)).collect();
+        let mut owning = TxProcessor::new();
+        let owned: Vec<_> = txs
+            .iter()
+            .map(|tx| owning.process_owned(*tx).map_err(|err| err.error))
+            .collect();
+        assert_eq!(borrowed, owned);
+        assert!(borrowing.client_summaries().eq(owning.client_summaries()));
+        assert_eq!(borrowing.export_status(), owning.export_status());
+        assert_eq!(borrowing.stats_by_kind(), owning.stats_by_kind());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let transfer = |from: u16, to: u16, tx_id: u64, a: &str| Tx::Transfer {
+            from: from.into(),
+            to: to.into(),
+            tx_id: tx_id.into(),
+            amount: amount(a),
+        };
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&transfer(1, 2, 2, "4")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, amount("6"));
+        assert_eq!(summary(&tx_proc, 2).available, amount("4"));
+        assert_eq!(
+            tx_proc.process(&transfer(1, 3, 2, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        // owned by the debited client, but not a withdrawal of it
+        assert_eq!(
+            tx_proc.process(&dispute(1, 2)),
+            Err(TxProcessingError::DepositNotFound)
+        );
+        assert_eq!(
+            tx_proc.process(&dispute(2, 2)),
+            Err(TxProcessingError::WrongClientForTx)
+        );
+
+        // insufficient source funds change nothing
+        assert_eq!(
+            tx_proc.process(&transfer(2, 1, 3, "5")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("6"));
+        assert_eq!(summary(&tx_proc, 2).available, amount("4"));
+
+        // nor does an overflowing destination
+        let max = "1844674407370955.1615";
+        tx_proc.process(&deposit(3, 4, max)).unwrap();
+        assert_eq!(
+            tx_proc.process(&transfer(1, 3, 5, "1")),
+            Err(TxProcessingError::AmountOverflow)
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("6"));
+        assert_eq!(summary(&tx_proc, 3).available, amount(max));
+        // the id stays unused
+        tx_proc.process(&transfer(1, 2, 5, "6")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, Amount::ZERO);
+        assert_eq!(summary(&tx_proc, 2).available, amount("10"));
+
+        tx_proc.freeze(2.into());
+        assert_eq!(
+            tx_proc.process(&transfer(3, 2, 6, "1")),
+            Err(TxProcessingError::AccountLocked)
+        );
+        assert_eq!(summary(&tx_proc, 3).available, amount(max));
+    }
+
+    #[test]
+    fn test_rejected_tx() {
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process_owned(deposit(1, 1, "10")).unwrap();
+        let rejected = tx_proc.process_owned(withdrawal(1, 2, "30"));
+        let rejected = rejected.unwrap_err();
+        assert_eq!(
+            rejected.tx,
             Tx::Withdrawal {
-                client_id, amount, ..
-            } => self.client(client_id)?.withdraw(amount),
-            Tx::Dispute { client_id, tx_id } => {
-                let amount = self.deposit_amount(client_id, tx_id)?;
-                if self.disputed.contains(&tx_id) {
-                    Err(TxProcessingError::TxAlreadyDisputed)
-                } else {
-                    self.client(client_id)?.dispute(amount)?;
-                    self.disputed.insert(tx_id);
-                    Ok(())
-                }
+                client_id: 1.into(),
+                tx_id: 2.into(),
+                amount: amount("30"),
             }
-            Tx::Resolve { client_id, tx_id } => {
-                let amount = self.deposit_amount(client_id, tx_id)?;
-                if self.disputed.remove(&tx_id) {
-                    Ok(self.client(client_id)?.resolve(amount))
-                } else {
-                    Err(TxProcessingError::TxNotDisputed)
-                }
+        );
+        assert_eq!(rejected.error, TxProcessingError::InsufficientFunds);
+        // the same as the CLI logs
+        assert_eq!(
+            rejected.to_string(),
+            format!("failed to process {:?}: insufficient funds", rejected.tx)
+        );
+        assert!(Error::source(&rejected).is_some());
+    }
+
+    #[test]
+    fn test_process_reporting() {
+        let mut tx_proc = TxProcessor::new();
+        let mut process = |tx| {
+            let processed = tx_proc.process_reporting(&tx);
+            (processed.result.is_ok(), processed.created_client)
+        };
+        assert_eq!(process(deposit(1, 1, "10")), (true, true));
+        assert_eq!(process(deposit(1, 2, "1")), (true, false));
+        assert_eq!(process(withdrawal(1, 3, "1")), (true, false));
+        // a rejected withdrawal still adds its client
+        assert_eq!(process(withdrawal(2, 4, "1")), (false, true));
+        assert_eq!(process(deposit(2, 5, "1")), (true, false));
+        // a dispute of an unknown deposit doesn't get that far
+        assert_eq!(process(dispute(3, 9)), (false, false));
+        assert_eq!(process(deposit(3, 6, "1")), (true, true));
+        // a transfer can add the credited client
+        let transfer = Tx::Transfer {
+            from: 1.into(),
+            to: 4.into(),
+            tx_id: 7.into(),
+            amount: amount("1"),
+        };
+        assert_eq!(process(transfer), (true, true));
+    }
+
+    #[test]
+    fn test_duplicate_tx_ids() {
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        assert_eq!(
+            tx_proc.process(&deposit(1, 1, "5")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        assert_eq!(
+            tx_proc.process(&withdrawal(1, 1, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        // the id space is shared by all clients
+        tx_proc.process(&deposit(2, 2, "1")).unwrap();
+        assert_eq!(
+            tx_proc.process(&withdrawal(1, 2, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        tx_proc.process(&withdrawal(1, 3, "1")).unwrap();
+        assert_eq!(
+            tx_proc.process(&deposit(2, 3, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        // a rejected withdrawal doesn't take its id
+        assert_eq!(
+            tx_proc.process(&withdrawal(1, 4, "100")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+        tx_proc.process(&withdrawal(1, 4, "1")).unwrap();
+        assert_eq!(
+            tx_proc.process(&withdrawal(1, 4, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("8"));
+        assert_eq!(summary(&tx_proc, 2).available, amount("1"));
+        // disputes refer to ids rather than taking them
+        tx_proc.process(&dispute(1, 4)).unwrap();
+        tx_proc.process(&resolve(1, 4)).unwrap();
+        assert_eq!(
+            tx_proc.process(&deposit(1, 4, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        assert_eq!(summary(&tx_proc, 1).total, amount("8"));
+    }
+
+    #[test]
+    fn test_max_disputes_per_client() {
+        let mut tx_proc =
+            TxProcessor::builder().max_disputes_per_client(2).build();
+        for tx_id in 1..=4 {
+            tx_proc.process(&deposit(1, tx_id, "1")).unwrap();
+        }
+        tx_proc.process(&deposit(2, 5, "1")).unwrap();
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        tx_proc.process(&resolve(1, 1)).unwrap();
+        tx_proc.process(&dispute(1, 2)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).disputes, 2);
+        // resolved disputes still count
+        assert_eq!(
+            tx_proc.process(&dispute(1, 3)),
+            Err(TxProcessingError::ExcessiveDisputes)
+        );
+        assert_eq!(
+            tx_proc.dispute_matching(1.into(), |_, _| true),
+            Err(TxProcessingError::ExcessiveDisputes)
+        );
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.disputes, s.held), (2, amount("1")));
+        // the limit is per client
+        tx_proc.process(&dispute(2, 5)).unwrap();
+        assert_eq!(summary(&tx_proc, 2).disputes, 1);
+
+        // no limit by default
+        let mut tx_proc = TxProcessor::new();
+        for tx_id in 1..=3 {
+            tx_proc.process(&deposit(1, tx_id, "1")).unwrap();
+        }
+        assert_eq!(tx_proc.dispute_matching(1.into(), |_, _| true), Ok(3));
+        assert_eq!(summary(&tx_proc, 1).disputes, 3);
+    }
+
+    #[test]
+    fn test_coalesce_duplicate_deposits() {
+        let mut tx_proc = TxProcessor::builder()
+            .coalesce_duplicate_deposits(true)
+            .build();
+        tx_proc.process(&deposit(1, 1, "2")).unwrap();
+        tx_proc.process(&deposit(1, 1, "3")).unwrap();
+        let deposits: Vec<_> = tx_proc.client_deposits(1.into()).collect();
+        assert_eq!(deposits, vec![(1.into(), amount("5"))]);
+
+        // the dispute holds the sum, and so does a part arriving later
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        tx_proc.process(&deposit(1, 1, "1")).unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.held), (Amount::new().into(), amount("6")));
+        assert_eq!(s.disputes, 1);
+        tx_proc.process(&resolve(1, 1)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, amount("6"));
+
+        tx_proc
+            .process(&deposit(3, 2, "1844674407370955.1615"))
+            .unwrap();
+        assert_eq!(
+            tx_proc.process(&deposit(3, 2, "0.0001")),
+            Err(TxProcessingError::AmountOverflow)
+        );
+
+        // under FIFO the lot grows along with the deposit
+        let mut tx_proc = TxProcessor::builder()
+            .coalesce_duplicate_deposits(true)
+            .fifo(true)
+            .build();
+        tx_proc.process(&deposit(1, 1, "2")).unwrap();
+        tx_proc.process(&deposit(1, 1, "3")).unwrap();
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).held, amount("5"));
+    }
+
+    #[test]
+    fn test_coalesce_only_own_deposits() {
+        let mut tx_proc = TxProcessor::builder()
+            .coalesce_duplicate_deposits(true)
+            .build();
+        tx_proc.process(&deposit(1, 1, "2")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "1")).unwrap();
+        for tx in &[deposit(2, 1, "3"), deposit(1, 2, "3")] {
+            assert_eq!(
+                tx_proc.process(tx),
+                Err(TxProcessingError::DuplicateTxId)
+            );
+        }
+        assert_eq!(summary(&tx_proc, 1).total, amount("1"));
+    }
+
+    #[test]
+    fn test_wrong_client_for_tx() {
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&deposit(2, 2, "5")).unwrap();
+        assert_eq!(
+            tx_proc.process(&dispute(2, 1)),
+            Err(TxProcessingError::WrongClientForTx)
+        );
+        assert_eq!(
+            tx_proc.process(&dispute(1, 3)),
+            Err(TxProcessingError::DepositNotFound)
+        );
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        assert_eq!(
+            tx_proc.process(&resolve(2, 1)),
+            Err(TxProcessingError::WrongClientForTx)
+        );
+        assert_eq!(
+            tx_proc.process(&Tx::Chargeback {
+                client_id: 3.into(),
+                tx_id: 2.into(),
+            }),
+            Err(TxProcessingError::WrongClientForTx)
+        );
+        assert_eq!(summary(&tx_proc, 1).held, amount("10"));
+        assert_eq!(summary(&tx_proc, 2).held, Amount::new());
+
+        // a failed withdrawal has no owner
+        let _ = tx_proc.process(&withdrawal(1, 4, "100"));
+        assert_eq!(
+            tx_proc.process(&dispute(2, 4)),
+            Err(TxProcessingError::DepositNotFound)
+        );
+    }
+
+    #[test]
+    fn test_dispute_is_per_tx_of_one_client() {
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 5, "10")).unwrap();
+        tx_proc.process(&dispute(1, 5)).unwrap();
+        // another client can't record tx 5 or touch its dispute
+        assert_eq!(
+            tx_proc.process(&deposit(2, 5, "3")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+        tx_proc.process(&deposit(2, 6, "3")).unwrap();
+        for tx in &[dispute(2, 5), resolve(2, 5)] {
+            assert_eq!(
+                tx_proc.process(tx),
+                Err(TxProcessingError::WrongClientForTx)
+            );
+        }
+        assert_eq!(summary(&tx_proc, 2).held, Amount::ZERO);
+        assert_eq!(summary(&tx_proc, 1).held, amount("10"));
+        let disputed: Vec<(TxID, ClientID)> = tx_proc
+            .export_status()
+            .into_iter()
+            .filter(|entry| entry.status == TxStatus::Disputed)
+            .map(|entry| (entry.tx, entry.client))
+            .collect();
+        assert_eq!(disputed, [(5.into(), 1.into())]);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut tx_proc = TxProcessor::new();
+        for tx_id in 1..=100 {
+            tx_proc.process(&deposit(1, tx_id, "1")).unwrap();
+        }
+        tx_proc.process(&withdrawal(1, 101, "1")).unwrap();
+        tx_proc.process(&deposit(2, 102, "1")).unwrap();
+        tx_proc.process(&withdrawal(2, 103, "1")).unwrap();
+        tx_proc.process(&dispute(1, 7)).unwrap();
+        tx_proc.process(&dispute(1, 8)).unwrap();
+        tx_proc.process(&resolve(1, 8)).unwrap();
+        let capacity = tx_proc.deposits.capacity();
+        assert_eq!(tx_proc.retained_txs(), 103);
+
+        tx_proc.compact(102.into());
+        assert_eq!(tx_proc.retained_txs(), 3);
+        assert!(tx_proc.deposits.capacity() < capacity);
+        assert!(!tx_proc.withdrawals.contains_key(&101.into()));
+        assert!(tx_proc.withdrawals.contains_key(&103.into()));
+        let deposits: Vec<_> = tx_proc.client_deposits(1.into()).collect();
+        assert_eq!(deposits, vec![(7.into(), amount("1"))]);
+        let deposits: Vec<_> = tx_proc.client_deposits(2.into()).collect();
+        assert_eq!(deposits, vec![(102.into(), amount("1"))]);
+
+        // balances are untouched, the disputed deposit can still be settled
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.held), (amount("98").into(), amount("1")));
+        tx_proc.process(&resolve(1, 7)).unwrap();
+        assert_eq!(
+            tx_proc.process(&dispute(1, 8)),
+            Err(TxProcessingError::DepositNotFound)
+        );
+        // a forgotten id is still taken
+        assert_eq!(
+            tx_proc.process(&deposit(1, 8, "1")),
+            Err(TxProcessingError::DuplicateTxId)
+        );
+    }
+
+    #[test]
+    fn test_hash_seed() {
+        let client_order = |seed| {
+            let mut tx_proc = TxProcessor::builder().hash_seed(seed).build();
+            for client_id in 1..=50 {
+                let tx_id = client_id.into();
+                tx_proc.process(&deposit(client_id, tx_id, "1")).unwrap();
             }
-            Tx::Chargeback { client_id, tx_id } => {
-                let amount = self.deposit_amount(client_id, tx_id)?;
-                if self.disputed.remove(&tx_id) {
-                    Ok(self.client(client_id)?.chargeback(amount))
-                } else {
-                    Err(TxProcessingError::TxNotDisputed)
-                }
+            tx_proc.clients.keys().copied().collect::<Vec<ClientID>>()
+        };
+        assert_eq!(client_order(42), client_order(42));
+        assert_ne!(client_order(42), client_order(43));
+    }
+
+    #[test]
+    fn test_verify_held_consistency() {
+        let mut tx_proc = TxProcessor::builder()
+            .allow_negative_available(true)
+            .build();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            withdrawal(1, 3, "12"),
+            deposit(2, 4, "3"),
+            dispute(1, 1),
+            dispute(1, 3),
+            dispute(2, 4),
+            resolve(2, 4),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        assert_eq!(tx_proc.verify_held_consistency(), Ok(()));
+
+        // held without a recorded dispute
+        tx_proc.clients.get_mut(&2.into()).unwrap().held = 30000;
+        assert_eq!(
+            tx_proc.verify_held_consistency(),
+            Err(TxProcessingError::InvariantViolation)
+        );
+        tx_proc.clients.get_mut(&2.into()).unwrap().held = 0;
+        // a recorded dispute holding nothing
+        tx_proc.disputed.insert(2.into());
+        assert_eq!(
+            tx_proc.verify_held_consistency(),
+            Err(TxProcessingError::InvariantViolation)
+        );
+    }
+
+    #[test]
+    fn test_client_summaries_page() {
+        let mut tx_proc = TxProcessor::new();
+        for (tx_id, client_id) in [9, 3, 14, 1, 7, 12, 5].iter().enumerate() {
+            tx_proc
+                .process(&deposit(*client_id, tx_id as u64, "1"))
+                .unwrap();
+        }
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = tx_proc.client_summaries_page(after, 3);
+            if page.is_empty() {
+                break;
             }
+            after = page.last().map(|s| s.id);
+            pages.push(page.iter().map(|s| s.id).collect::<Vec<_>>());
         }
+        let ids = |ids: &[u16]| -> Vec<ClientID> {
+            ids.iter().map(|id| (*id).into()).collect()
+        };
+        assert_eq!(pages, vec![ids(&[1, 3, 5]), ids(&[7, 9, 12]), ids(&[14])]);
+
+        // a cursor needn't be an existing client
+        let page = tx_proc.client_summaries_page(Some(8.into()), 10);
+        assert_eq!(page.len(), 3);
+        assert_eq!(page[0], summary(&tx_proc, 9));
+        assert!(tx_proc.client_summaries_page(None, 0).is_empty());
     }
 
-    pub fn client_summaries<'a>(
-        &'a self,
-    ) -> impl Iterator<Item = ClientSummary> + 'a {
-        self.clients
+    #[test]
+    fn test_summarize() {
+        let summaries = summarize(&[
+            deposit(2, 1, "5"),
+            deposit(1, 2, "2.5"),
+            withdrawal(1, 3, "10"),
+            dispute(2, 1),
+        ]);
+        let balances: Vec<_> = summaries
             .iter()
-            .map(|(client_id, client)| ClientSummary {
-                id: *client_id,
-                available: client.available,
-                held: client.held,
-                total: client.total(),
-                locked: client.locked,
-            })
+            .map(|s| (s.id, s.available, s.held))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                (1.into(), amount("2.5").into(), Amount::new()),
+                (2.into(), SignedAmount::new(), amount("5")),
+            ]
+        );
     }
 
-    fn deposit_amount(
-        &self,
-        client_id: ClientID,
-        tx_id: TxID,
-    ) -> Result<Amount, TxProcessingError> {
-        let client_deposits = self
-            .deposits
-            .get(&client_id)
-            .ok_or(TxProcessingError::DepositNotFound)?;
-        client_deposits
-            .get(&tx_id)
-            .map(|x| x.clone())
-            .ok_or(TxProcessingError::DepositNotFound)
+    #[test]
+    fn test_reserve_client_id() {
+        let mut tx_proc =
+            TxProcessor::builder().reserve_client_id(0.into()).build();
+        assert_eq!(
+            tx_proc.process(&deposit(0, 1, "1")),
+            Err(TxProcessingError::ReservedClientId)
+        );
+        assert_eq!(
+            tx_proc.process(&dispute(0, 1)),
+            Err(TxProcessingError::ReservedClientId)
+        );
+        tx_proc.process(&deposit(1, 2, "1")).unwrap();
+        assert!(tx_proc.client_summary(0.into()).is_none());
+        // like processing the withdrawal would
+        assert_eq!(
+            tx_proc.can_withdraw(0.into(), amount("1")),
+            Err(TxProcessingError::ReservedClientId)
+        );
+        assert_eq!(tx_proc.can_withdraw(1.into(), amount("1")), Ok(()));
+
+        // nothing is reserved by default
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(0, 1, "1")).unwrap();
     }
 
-    fn client(
-        &mut self,
-        client_id: ClientID,
-    ) -> Result<&mut Client, TxProcessingError> {
-        let client = self.clients.entry(client_id).or_insert(Client::new());
-        if client.locked {
-            Err(TxProcessingError::AccountLocked)
-        } else {
-            Ok(client)
+    #[test]
+    fn test_fifo() {
+        let txs = [
+            deposit(1, 1, "10"),
+            deposit(1, 2, "10"),
+            deposit(1, 3, "10"),
+            withdrawal(1, 4, "15"),
+        ];
+        let mut tx_proc = TxProcessor::builder().fifo(true).build();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
+        }
+        // enough is available, but under FIFO the withdrawal took all of
+        // the first deposit and half of the second one
+        assert_eq!(
+            tx_proc.process(&dispute(1, 1)),
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        );
+        assert_eq!(
+            tx_proc.process(&dispute(1, 2)),
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        );
+        assert_eq!(
+            tx_proc.dispute_matching(1.into(), |_, _| true),
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        );
+        tx_proc.process(&dispute(1, 3)).unwrap();
+
+        // withdrawals skip the held third deposit
+        tx_proc.process(&withdrawal(1, 5, "5")).unwrap();
+        tx_proc.process(&resolve(1, 3)).unwrap();
+        tx_proc.process(&withdrawal(1, 6, "1")).unwrap();
+        assert_eq!(
+            tx_proc.process(&dispute(1, 3)),
+            Err(TxProcessingError::CannotHoldInsufficientAvailable)
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("9"));
+
+        // opening balances are the oldest funds
+        let mut tx_proc = TxProcessor::builder()
+            .opening_balances(
+                vec![(1.into(), amount("5"))].into_iter().collect(),
+            )
+            .fifo(true)
+            .build();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "5")).unwrap();
+        tx_proc.process(&dispute(1, 1)).unwrap();
+
+        // only the aggregate is checked by default
+        let mut tx_proc = TxProcessor::new();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
         }
+        tx_proc.process(&dispute(1, 1)).unwrap();
     }
-}
 
-#[derive(Debug)]
-struct Client {
-    available: Amount,
-    held: Amount,
-    locked: bool,
-}
+    #[test]
+    fn test_dispute_withdrawal() {
+        let mut tx_proc = TxProcessor::builder()
+            .allow_negative_available(true)
+            .build();
+        for tx in &[
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            deposit(2, 3, "10"),
+            withdrawal(2, 4, "4"),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        let balances = |tx_proc: &TxProcessor, client_id| {
+            let s = summary(tx_proc, client_id);
+            (
+                s.available.to_string(),
+                s.held.to_string(),
+                s.total.to_string(),
+            )
+        };
+        let b = |available: &str, held: &str, total: &str| {
+            (available.to_string(), held.to_string(), total.to_string())
+        };
 
-// invariant: total == available + held
-// invariant: total should be representable as Amount
-impl Client {
-    fn new() -> Self {
-        Self {
-            available: Amount::new(),
-            held: Amount::new(),
-            locked: false,
+        // a credit moves the deposit from available to held, a debit holds
+        // the withdrawn funds on top of the total
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        tx_proc.process(&dispute(2, 4)).unwrap();
+        assert_eq!(balances(&tx_proc, 1), b("-4", "10", "6"));
+        assert_eq!(balances(&tx_proc, 2), b("6", "4", "10"));
+        assert!(invariants_hold(&tx_proc));
+        assert_eq!(
+            tx_proc.process(&dispute(2, 4)),
+            Err(TxProcessingError::TxAlreadyDisputed)
+        );
+
+        // resolving a debit leaves the withdrawal standing
+        tx_proc.process(&resolve(2, 4)).unwrap();
+        assert_eq!(balances(&tx_proc, 2), b("6", "0", "6"));
+
+        // charging it back returns the funds
+        tx_proc.process(&dispute(2, 4)).unwrap();
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 2.into(),
+                tx_id: 4.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 2);
+        assert_eq!(balances(&tx_proc, 2), b("10", "0", "10"));
+        assert!(s.locked);
+        assert_eq!(
+            tx_proc.lock_reason(2.into()),
+            LockReason::Chargeback(4.into())
+        );
+        assert!(invariants_hold(&tx_proc));
+    }
+
+    #[test]
+    fn test_dispute_withdrawal_status() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            withdrawal(1, 3, "1"),
+            dispute(1, 2),
+            dispute(1, 3),
+            resolve(1, 3),
+        ] {
+            tx_proc.process(tx).unwrap();
+        }
+        assert_eq!(
+            tx_proc.process(&dispute(2, 2)),
+            Err(TxProcessingError::WrongClientForTx)
+        );
+        let statuses: Vec<_> = tx_proc
+            .export_status()
+            .into_iter()
+            .map(|entry| (entry.tx, entry.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (1.into(), TxStatus::Deposited),
+                (2.into(), TxStatus::Disputed),
+                (3.into(), TxStatus::Resolved),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_status() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "2"),
+            deposit(1, 3, "3"),
+            withdrawal(1, 4, "1.5"),
+            deposit(2, 5, "5"),
+            dispute(1, 2),
+            dispute(1, 3),
+            resolve(1, 3),
+            dispute(2, 5),
+            Tx::Chargeback {
+                client_id: 2.into(),
+                tx_id: 5.into(),
+            },
+        ] {
+            tx_proc.process(tx).unwrap();
         }
+        assert_eq!(
+            tx_proc.export_status_json(),
+            concat!(
+                r#"[{"tx":1,"client":1,"kind":"deposit","amount":"10","#,
+                r#""status":"deposited"},"#,
+                r#"{"tx":2,"client":1,"kind":"deposit","amount":"2","#,
+                r#""status":"disputed"},"#,
+                r#"{"tx":3,"client":1,"kind":"deposit","amount":"3","#,
+                r#""status":"resolved"},"#,
+                r#"{"tx":4,"client":1,"kind":"withdrawal","amount":"1.5","#,
+                r#""status":"withdrawn"},"#,
+                r#"{"tx":5,"client":2,"kind":"deposit","amount":"5","#,
+                r#""status":"charged-back"}]"#,
+            )
+        );
+        assert_eq!(TxProcessor::new().export_status_json(), "[]");
     }
 
-    fn total(&self) -> Amount {
-        self.available
-            .checked_add(self.held)
-            .expect("invariant violated: total is too big")
+    #[test]
+    fn test_validator() {
+        let limit = amount("10");
+        let mut tx_proc = TxProcessor::builder()
+            .validator(move |tx, tx_proc| match *tx {
+                Tx::Deposit {
+                    client_id, amount, ..
+                } if tx_proc.client_summary(client_id).is_none()
+                    && amount.checked_sub(limit).is_some() =>
+                {
+                    Err(format!("first deposit of {} is too large", amount))
+                }
+                _ => Ok(()),
+            })
+            .build();
+        assert_eq!(
+            tx_proc.process(&deposit(1, 1, "10")),
+            Err(TxProcessingError::Rejected(
+                "first deposit of 10 is too large".to_string()
+            ))
+        );
+        assert!(tx_proc.client_summary(1.into()).is_none());
+        tx_proc.process(&deposit(1, 2, "9")).unwrap();
+        tx_proc.process(&deposit(1, 3, "100")).unwrap();
+        tx_proc.process(&withdrawal(1, 4, "50")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, amount("59"));
+        assert_eq!(
+            tx_proc
+                .process(&deposit(2, 5, "11"))
+                .unwrap_err()
+                .to_string(),
+            "rejected: first deposit of 11 is too large"
+        );
     }
 
-    fn deposit(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
-        if self.total().checked_add(amount).is_some() {
-            Ok(self.available = self
-                .available
-                .checked_add(amount)
-                .expect("invariant violated: total < available"))
-        } else {
+    #[test]
+    fn test_client_chain_hash() {
+        let hashes = |txs: &[Tx]| {
+            let mut tx_proc = TxProcessor::new();
+            for tx in txs {
+                let _ = tx_proc.process(tx);
+            }
+            (
+                tx_proc.client_chain_hash(1.into()),
+                tx_proc.client_chain_hash(2.into()),
+            )
+        };
+        let txs = [
+            deposit(1, 1, "10"),
+            deposit(2, 2, "1"),
+            withdrawal(1, 3, "4"),
+            withdrawal(1, 4, "100"),
+            dispute(1, 1),
+        ];
+        let (one, two) = hashes(&txs);
+        assert!(one.is_some() && two.is_some());
+        assert_eq!(hashes(&txs), (one, two));
+        assert_eq!(TxProcessor::new().client_chain_hash(1.into()), None);
+        // pinned, so that a change of the encoding is noticed
+        assert_eq!(hashes(&txs[..1]).0, Some(0x484c_d852_7910_b678));
+
+        // failed transactions aren't part of the chain
+        let applied = [txs[0], txs[1], txs[2], txs[4]];
+        assert_eq!(hashes(&applied), (one, two));
+
+        let reordered = [txs[2], txs[1], txs[0], txs[3], txs[4]];
+        let (reordered_one, reordered_two) = hashes(&reordered);
+        assert_ne!(reordered_one, one);
+        assert_eq!(reordered_two, two);
+    }
+
+    #[test]
+    fn test_disputed_deposit_overflow() {
+        let mut tx_proc = TxProcessor::builder()
+            .allow_negative_available(true)
+            .disputed(vec![5.into()].into_iter().collect())
+            .build();
+        tx_proc.process(&deposit(1, 1, "0.0001")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "0.0001")).unwrap();
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        let before = summary(&tx_proc, 1);
+
+        // credited it would fit, but not held on top of the first hold
+        let huge = Tx::Deposit {
+            client_id: 1.into(),
+            tx_id: 5.into(),
+            amount: Amount::from_minor_units(u64::MAX),
+        };
+        assert!(matches!(
+            tx_proc.process(&huge),
             Err(TxProcessingError::AmountOverflow)
+        ));
+        assert_eq!(summary(&tx_proc, 1), before);
+        // nor is the id taken
+        tx_proc.process(&deposit(1, 5, "1")).unwrap();
+        assert_eq!(summary(&tx_proc, 1).held, amount("1.0001"));
+    }
+
+    #[test]
+    fn test_with_disputed() {
+        let mut tx_proc = TxProcessor::with_disputed(
+            vec![2.into(), 3.into()].into_iter().collect(),
+        );
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&deposit(1, 2, "5")).unwrap();
+        tx_proc.process(&deposit(1, 3, "1")).unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.available, amount("10"));
+        assert_eq!(s.held, amount("6"));
+        assert_eq!(s.total, amount("16"));
+
+        let dispute = Tx::Dispute {
+            client_id: 1.into(),
+            tx_id: 2.into(),
+        };
+        assert!(matches!(
+            tx_proc.process(&dispute),
+            Err(TxProcessingError::TxAlreadyDisputed)
+        ));
+        tx_proc
+            .process(&Tx::Resolve {
+                client_id: 1.into(),
+                tx_id: 2.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.available, amount("15"));
+        assert_eq!(s.held, amount("1"));
+
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 3.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.held, Amount::new());
+        assert_eq!(s.total, amount("15"));
+        assert!(s.locked);
+    }
+
+    fn corrupt(tx_proc: &mut TxProcessor) {
+        tx_proc.process(&deposit(1, 1, "1")).unwrap();
+        tx_proc.process(&deposit(2, 2, "1")).unwrap();
+        tx_proc.process(&deposit(3, 3, "1")).unwrap();
+        let client = tx_proc.clients.get_mut(&2.into()).unwrap();
+        client.available = units(amount("1844674407370955.1615"));
+        client.held = units(amount("1"));
+    }
+
+    #[test]
+    fn test_checked_client_summaries() {
+        let mut tx_proc = TxProcessor::new();
+        corrupt(&mut tx_proc);
+        let mut errors = Vec::new();
+        let summaries = tx_proc
+            .checked_client_summaries()
+            .filter_map(|s| s.map_err(|err| errors.push(err)).ok().map(Ok));
+        let mut output = Vec::new();
+        write_summaries(&mut output, summaries, Columns::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,1,0,1,false
+3,1,0,1,false
+"
+        );
+        let client_id = 2.into();
+        assert_eq!(errors, [InconsistentClient { client_id }]);
+        assert_eq!(errors[0].to_string(), "client 2: invariant violated");
+    }
+
+    #[test]
+    fn test_inconsistent_clients_left_out() {
+        let mut tx_proc = TxProcessor::new();
+        corrupt(&mut tx_proc);
+        let ids: Vec<ClientID> =
+            tx_proc.client_summaries().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1.into(), 3.into()]);
+        let page = tx_proc.client_summaries_page(None, 3);
+        assert_eq!(page.len(), 2);
+        assert!(tx_proc.client_summary(2.into()).is_none());
+        let errors: Vec<InconsistentClient> = tx_proc
+            .client_summaries_unsorted()
+            .filter_map(Result::err)
+            .collect();
+        assert_eq!(
+            errors,
+            [InconsistentClient {
+                client_id: 2.into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_client_ledgers() {
+        let mut tx_proc = TxProcessor::new();
+        for tx in &[
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "3"),
+            deposit(2, 3, "5"),
+            withdrawal(2, 4, "1"),
+            withdrawal(1, 5, "100"),
+            withdrawal(1, 6, "0.5"),
+        ] {
+            let _ = tx_proc.process(tx);
         }
+        let sorted = |it: &mut dyn Iterator<Item = (TxID, Amount)>| {
+            let mut v: Vec<(TxID, Amount)> = it.collect();
+            v.sort_unstable_by_key(|(tx_id, _)| *tx_id);
+            v
+        };
+        assert_eq!(
+            sorted(&mut tx_proc.client_withdrawals(1.into())),
+            vec![(2.into(), amount("3")), (6.into(), amount("0.5"))]
+        );
+        assert_eq!(
+            sorted(&mut tx_proc.client_deposits(1.into())),
+            vec![(1.into(), amount("10"))]
+        );
+        assert_eq!(
+            sorted(&mut tx_proc.client_withdrawals(2.into())),
+            vec![(4.into(), amount("1"))]
+        );
+        assert_eq!(tx_proc.client_withdrawals(3.into()).count(), 0);
+
+        let mut ledger: Vec<_> = tx_proc.tx_ledger().collect();
+        ledger.sort_unstable_by_key(|(tx_id, _, _)| *tx_id);
+        assert_eq!(
+            ledger,
+            vec![
+                (1.into(), 1.into(), TxKind::Deposit),
+                (2.into(), 1.into(), TxKind::Withdrawal),
+                (3.into(), 2.into(), TxKind::Deposit),
+                (4.into(), 2.into(), TxKind::Withdrawal),
+                (6.into(), 1.into(), TxKind::Withdrawal),
+            ]
+        );
     }
 
-    fn withdraw(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
-        match self.available.checked_sub(amount) {
-            Some(x) => Ok(self.available = x),
-            None => Err(TxProcessingError::InsufficientFunds),
+    #[test]
+    fn test_stats_by_kind() {
+        let mut tx_proc = TxProcessor::new();
+        let dispute = |tx_id: u64| Tx::Dispute {
+            client_id: 1.into(),
+            tx_id: tx_id.into(),
+        };
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "5"),
+            withdrawal(1, 3, "20"),
+            withdrawal(1, 4, "1"),
+            withdrawal(1, 5, "20"),
+            dispute(1),
+            dispute(1),
+            dispute(9),
+        ] {
+            let _ = tx_proc.process(tx);
         }
+
+        let stats = tx_proc.stats_by_kind();
+        let deposits = &stats[&TxKind::Deposit];
+        assert_eq!(deposits.applied, 2);
+        assert_eq!(deposits.rejected_total(), 0);
+
+        let withdrawals = &stats[&TxKind::Withdrawal];
+        assert_eq!(withdrawals.applied, 1);
+        assert_eq!(
+            withdrawals.rejected,
+            vec![(TxProcessingError::InsufficientFunds, 2)]
+                .into_iter()
+                .collect()
+        );
+
+        let disputes = &stats[&TxKind::Dispute];
+        assert_eq!(disputes.applied, 1);
+        assert_eq!(disputes.rejected_total(), 2);
+        assert_eq!(disputes.rejected[&TxProcessingError::TxAlreadyDisputed], 1);
+        assert_eq!(disputes.rejected[&TxProcessingError::DepositNotFound], 1);
+
+        assert!(stats.get(&TxKind::Chargeback).is_none());
     }
 
-    fn dispute(&mut self, amount: Amount) -> Result<(), TxProcessingError> {
-        match self.available.checked_sub(amount) {
-            Some(x) => {
-                self.held = self
-                    .held
-                    .checked_add(amount)
-                    .expect("invariant violated: total is too big");
-                Ok(self.available = x)
-            }
-            None => Err(TxProcessingError::InsufficientFunds),
+    #[test]
+    fn test_opening_balances() {
+        let mut tx_proc = TxProcessor::builder()
+            .opening_balances(
+                vec![(1.into(), amount("100"))].into_iter().collect(),
+            )
+            .build();
+        tx_proc.process(&deposit(1, 1, "20")).unwrap();
+        tx_proc.process(&withdrawal(1, 2, "50.5")).unwrap();
+        tx_proc.process(&deposit(2, 3, "7")).unwrap();
+
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.opening, amount("100"));
+        assert_eq!(s.closing, amount("69.5"));
+        assert_eq!(s.closing, s.total);
+        let s = summary(&tx_proc, 2);
+        assert_eq!(s.opening, Amount::new());
+        assert_eq!(s.closing, amount("7"));
+    }
+
+    #[test]
+    fn test_replay_with_hooks() {
+        use std::cell::RefCell;
+
+        let txs = vec![
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "20"),
+            deposit(1, 3, "5"),
+        ];
+        let events = RefCell::new(Vec::new());
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.replay_with_hooks(
+            &txs,
+            |tx| {
+                if let Tx::Deposit { tx_id, .. } = tx {
+                    events.borrow_mut().push(format!("before {}", tx_id))
+                }
+            },
+            |tx, result| {
+                if let Tx::Deposit { tx_id, .. } = tx {
+                    events.borrow_mut().push(format!("after {}", tx_id))
+                }
+                if result.is_err() {
+                    events.borrow_mut().push(format!("rejected {:?}", tx))
+                }
+            },
+        );
+        assert_eq!(
+            events.into_inner(),
+            vec![
+                "before 1".to_string(),
+                "after 1".to_string(),
+                format!("rejected {:?}", txs[1]),
+                "before 3".to_string(),
+                "after 3".to_string(),
+            ]
+        );
+        assert_eq!(summary(&tx_proc, 1).available, amount("15"));
+    }
+
+    #[test]
+    fn test_partial_hold_on_dispute() {
+        let mut tx_proc =
+            TxProcessor::builder().partial_hold_on_dispute(true).build();
+        for tx in &[
+            deposit(1, 1, "10"),
+            deposit(1, 2, "1"),
+            withdrawal(1, 3, "7"),
+            dispute(1, 1),
+        ] {
+            tx_proc.process(tx).unwrap();
         }
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.available, Amount::ZERO);
+        assert_eq!((s.held, s.total), (amount("4"), amount("4").into()));
+        assert_eq!(tx_proc.dispute_shortfall(1.into()), Some(amount("6")));
+        assert!(invariants_hold(&tx_proc));
+        assert_eq!(tx_proc.verify_held_consistency(), Ok(()));
+
+        // nothing is left for the next one, which holds nothing at all
+        tx_proc.process(&dispute(1, 2)).unwrap();
+        assert_eq!(tx_proc.dispute_shortfall(2.into()), Some(amount("1")));
+        assert_eq!(summary(&tx_proc, 1).held, amount("4"));
+
+        tx_proc.process(&resolve(1, 1)).unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.held), (amount("4").into(), Amount::ZERO));
+        assert_eq!(tx_proc.dispute_shortfall(1.into()), None);
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 2.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!((s.available, s.total), (amount("4").into(), s.available));
+        assert!(s.locked);
+        assert_eq!(tx_proc.verify_held_consistency(), Ok(()));
+
+        // fully available deposits are held as usual
+        let mut tx_proc =
+            TxProcessor::builder().partial_hold_on_dispute(true).build();
+        tx_proc.process(&deposit(1, 1, "10")).unwrap();
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).held, amount("10"));
+        assert_eq!(tx_proc.dispute_shortfall(1.into()), None);
     }
 
-    fn resolve(&mut self, amount: Amount) {
-        self.available = self
-            .available
-            .checked_add(amount)
-            .expect("invariant violated: total is too big");
-        self.held = self
-            .held
-            .checked_sub(amount)
-            .expect("not enough money is held");
+    #[test]
+    fn test_allow_negative_available() {
+        let txs = [deposit(1, 1, "10"), withdrawal(1, 2, "7"), dispute(1, 1)];
+        let mut tx_proc = TxProcessor::new();
+        let results: Vec<_> =
+            txs.iter().map(|tx| tx_proc.process(tx)).collect();
+        let cannot_hold = TxProcessingError::CannotHoldInsufficientAvailable;
+        assert_eq!(results, vec![Ok(()), Ok(()), Err(cannot_hold)]);
+
+        let mut tx_proc = TxProcessor::builder()
+            .allow_negative_available(true)
+            .build();
+        for tx in &txs {
+            tx_proc.process(tx).unwrap();
+        }
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.available.to_string(), "-7");
+        assert_eq!((s.held, s.total), (amount("10"), amount("3").into()));
+        assert!(invariants_hold(&tx_proc));
+        // nothing can be withdrawn while available is negative
+        assert_eq!(
+            tx_proc.process(&withdrawal(1, 3, "0.0001")),
+            Err(TxProcessingError::InsufficientFunds)
+        );
+
+        tx_proc.process(&resolve(1, 1)).unwrap();
+        assert_eq!(summary(&tx_proc, 1).available, amount("3"));
+        tx_proc.process(&dispute(1, 1)).unwrap();
+        tx_proc
+            .process(&Tx::Chargeback {
+                client_id: 1.into(),
+                tx_id: 1.into(),
+            })
+            .unwrap();
+        let s = summary(&tx_proc, 1);
+        assert_eq!(s.total.to_string(), "-7");
+        assert_eq!((s.available, s.held), (s.total, Amount::new()));
+        assert!(s.locked);
+        assert!(invariants_hold(&tx_proc));
     }
 
-    fn chargeback(&mut self, amount: Amount) {
-        self.held = self
-            .held
-            .checked_sub(amount)
-            .expect("not enough money is held");
-        self.locked = true
+    #[test]
+    fn test_deposit_overflow_after_dispute() {
+        let max = "1844674407370955.1615";
+        let mut tx_proc = TxProcessor::new();
+        tx_proc.process(&deposit(1, 1, max)).unwrap();
+        tx_proc
+            .process(&Tx::Dispute {
+                client_id: 1.into(),
+                tx_id: 1.into(),
+            })
+            .unwrap();
+        assert_eq!(summary(&tx_proc, 1).held, amount(max));
+        assert!(matches!(
+            tx_proc.process(&deposit(1, 2, "0.0001")),
+            Err(TxProcessingError::AmountOverflow)
+        ));
+
+        // even if available + held already overflows
+        let mut client = Client::new();
+        client.held = units(amount(max));
+        client.available = units(amount("1"));
+        assert!(matches!(
+            client.deposit(amount("1")),
+            Err(TxProcessingError::AmountOverflow)
+        ));
+        assert_eq!(client.available, units(amount("1")));
     }
-}
 
-impl Display for TxProcessingError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            Self::AmountOverflow => write!(f, "amount overflow"),
-            Self::InsufficientFunds => write!(f, "insufficient funds"),
-            Self::DepositNotFound => write!(f, "deposit not found"),
-            Self::TxAlreadyDisputed => {
-                write!(f, "transaction is already disputed")
+    impl Arbitrary for ClientSummary {
+        fn arbitrary(g: &mut Gen) -> ClientSummary {
+            let (a, b) = (Amount::arbitrary(g), Amount::arbitrary(g));
+            let (available, held) = if bool::arbitrary(g) {
+                // a negative available never takes total out of range
+                (SignedAmount::new().checked_sub(a).unwrap(), b)
+            } else {
+                match a.checked_sub(b) {
+                    Some(x) => (x.into(), b),
+                    None => (b.checked_sub(a).unwrap().into(), a),
+                }
+            };
+            let total = available.checked_add(held).unwrap();
+            ClientSummary {
+                id: u16::arbitrary(g).into(),
+                available,
+                held,
+                total,
+                locked: bool::arbitrary(g),
+                opening: Amount::arbitrary(g),
+                closing: total,
+                // not part of the CSV output
+                disputes: 0,
+                deposit_count: u64::arbitrary(g),
+                withdrawal_count: u64::arbitrary(g),
+                active_disputes: u64::arbitrary(g),
             }
-            Self::TxNotDisputed => write!(f, "transaction is not disputed"),
-            Self::AccountLocked => write!(f, "account is locked"),
         }
     }
-}
-impl Error for TxProcessingError {}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use quickcheck::{Arbitrary, Gen};
 
     #[derive(Debug, Clone)]
     struct Txs(Vec<Tx>);
@@ -238,10 +3302,10 @@ mod tests {
                 tx_id: 0.into(),
                 amount: Amount::arbitrary(g),
             }];
-            let mut next_deposit: u32 = 1;
-            let mut next_withdrawal: u32 = size as u32;
+            let mut next_deposit: u64 = 1;
+            let mut next_withdrawal: u64 = size as u64;
             let gen_tx_id =
-                |g: &mut Gen, n: u32| (u32::arbitrary(g) % n).into();
+                |g: &mut Gen, n: u64| (u64::arbitrary(g) % n).into();
             for _ in 1..size {
                 txs.push(match u32::arbitrary(g) % 41 {
                     0..=9 => {
@@ -282,6 +3346,102 @@ mod tests {
         }
     }
 
+    // transactions of several clients interleaved; disputes sometimes name
+    // a client other than the owner of the deposit or withdrawal
+    #[derive(Debug, Clone)]
+    struct MultiClientTxs(Vec<Tx>);
+
+    impl Random for Gen {
+        fn next_u64(&mut self) -> u64 {
+            u64::arbitrary(self)
+        }
+    }
+
+    impl Arbitrary for MultiClientTxs {
+        fn arbitrary(g: &mut Gen) -> MultiClientTxs {
+            const CLIENTS: u16 = 4;
+            let size = usize::arbitrary(g) % g.size();
+            let max_amount = Amount::from_minor_units(u64::MAX);
+            let txs: Vec<Tx> = TxGenerator::new(&mut *g, CLIENTS, max_amount)
+                .take(size)
+                .collect();
+            let txs = txs.into_iter().map(|tx| {
+                if bool::arbitrary(g) {
+                    return tx;
+                }
+                let client_id = (u16::arbitrary(g) % CLIENTS).into();
+                match tx {
+                    Tx::Dispute { tx_id, .. } => {
+                        Tx::Dispute { client_id, tx_id }
+                    }
+                    Tx::Resolve { tx_id, .. } => {
+                        Tx::Resolve { client_id, tx_id }
+                    }
+                    Tx::Chargeback { tx_id, .. } => {
+                        Tx::Chargeback { client_id, tx_id }
+                    }
+                    tx => tx,
+                }
+            });
+            MultiClientTxs(txs.collect())
+        }
+    }
+
+    // invariant: the balances fit a summary, and held is exactly the sum of
+    // the client's disputed deposits and withdrawals
+    fn invariants_hold(tx_proc: &TxProcessor) -> bool {
+        tx_proc.clients.iter().all(|(client_id, client)| {
+            let held = tx_proc
+                .client_deposits(*client_id)
+                .chain(tx_proc.client_withdrawals(*client_id))
+                .filter(|(tx_id, _)| tx_proc.disputed.contains(tx_id))
+                .try_fold(Amount::new(), |acc, (tx_id, amount)| {
+                    acc.checked_add(tx_proc.held_for_dispute(tx_id, amount))
+                });
+            client.total().is_some() && held == client.held()
+        })
+    }
+
+    // a single client with amounts small enough for withdrawals to succeed,
+    // so disputes often hit them
+    fn invariants_hold_with_withdrawal_disputes(ops: Vec<(u8, u16)>) -> bool {
+        let mut tx_proc = TxProcessor::new();
+        let mut recorded: Vec<TxID> = Vec::new();
+        ops.into_iter().enumerate().all(|(i, (kind, n))| {
+            let client_id = 1.into();
+            let tx_id: TxID = (i as u64).into();
+            let amount = Amount::from_minor_units(u64::from(n));
+            let tx = match (kind % 9, recorded.len()) {
+                (0..=2, _) | (_, 0) => {
+                    recorded.push(tx_id);
+                    Tx::Deposit {
+                        client_id,
+                        tx_id,
+                        amount,
+                    }
+                }
+                (3..=4, _) => {
+                    recorded.push(tx_id);
+                    Tx::Withdrawal {
+                        client_id,
+                        tx_id,
+                        amount,
+                    }
+                }
+                (k, len) => {
+                    let tx_id = recorded[usize::from(n) % len];
+                    match k {
+                        5..=6 => Tx::Dispute { client_id, tx_id },
+                        7 => Tx::Resolve { client_id, tx_id },
+                        _ => Tx::Chargeback { client_id, tx_id },
+                    }
+                }
+            };
+            let _ = tx_proc.process(&tx);
+            invariants_hold(&tx_proc)
+        })
+    }
+
     quickcheck! {
         fn prop_amounts_are_correct(txs: Txs) -> bool {
             let mut available = Amount::new();
@@ -294,7 +3454,14 @@ mod tests {
             let Txs(txs) = txs;
             for tx in txs {
                 if tx_proc.process(&tx).is_ok() {
-                    assert!(!locked);
+                    // only disputes opened before can be settled
+                    assert!(
+                        !locked
+                            || matches!(
+                                tx,
+                                Tx::Resolve { .. } | Tx::Chargeback { .. }
+                            )
+                    );
                     match tx {
                         Tx::Deposit{tx_id, amount, ..} => {
                             deposit_amounts.insert(tx_id, amount);
@@ -321,13 +3488,74 @@ mod tests {
                             total = total.checked_sub(amount).unwrap();
                             locked = true
                         }
+                        Tx::Transfer{..} => unreachable!("one client only"),
                     }
                 }
             }
 
             let s = tx_proc.client_summaries().nth(0).unwrap();
             s.available == available && s.held == held &&
-                s.total == total && s.locked == locked
+                s.total == total && s.locked == locked &&
+                tx_proc.verify_held_consistency().is_ok()
+        }
+
+        fn prop_invariants_hold_after_every_tx(txs: MultiClientTxs) -> bool {
+            let mut tx_proc = TxProcessor::new();
+            let MultiClientTxs(txs) = txs;
+            txs.iter().all(|tx| {
+                let _ = tx_proc.process(tx);
+                invariants_hold(&tx_proc)
+            })
+        }
+
+        fn prop_invariants_hold_with_withdrawal_disputes(
+            ops: Vec<(u8, u16)>
+        ) -> bool {
+            invariants_hold_with_withdrawal_disputes(ops)
+        }
+
+        fn prop_invariants_hold_with_negative_available(
+            txs: MultiClientTxs
+        ) -> bool {
+            let mut tx_proc =
+                TxProcessor::builder().allow_negative_available(true).build();
+            let MultiClientTxs(txs) = txs;
+            txs.iter().all(|tx| {
+                let _ = tx_proc.process(tx);
+                invariants_hold(&tx_proc)
+            })
+        }
+
+        // amounts close to the largest one, which used to overflow the
+        // total: the processor rejects what doesn't fit instead of
+        // panicking, and the summary always has the accepted sum
+        fn prop_large_deposits_never_panic(ops: Vec<(u8, bool)>) -> bool {
+            let mut tx_proc = TxProcessor::builder()
+                .allow_negative_available(true)
+                .build();
+            let mut total = Amount::new();
+            ops.into_iter().enumerate().all(|(i, (shift, dispute))| {
+                let amount = Amount::from_minor_units(u64::MAX >> (shift % 3));
+                let tx_id = TxID::from(i as u64);
+                let fits = total.checked_add(amount).is_some();
+                match tx_proc.process(&Tx::Deposit {
+                    client_id: 1.into(),
+                    tx_id,
+                    amount,
+                }) {
+                    Ok(()) => total = total + amount,
+                    Err(TxProcessingError::AmountOverflow) if !fits => {}
+                    Err(_) => return false,
+                }
+                if dispute {
+                    let _ = tx_proc.process(&Tx::Dispute {
+                        client_id: 1.into(),
+                        tx_id,
+                    });
+                }
+                summary(&tx_proc, 1).total == total
+                    && invariants_hold(&tx_proc)
+            })
         }
     }
 }
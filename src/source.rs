@@ -0,0 +1,694 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use crate::diff::{write_summaries, Columns};
+use crate::json::{parse_flat_object, ParseJsonError};
+use crate::process::TxProcessor;
+use crate::tx::{Tx, TxID};
+
+/// Anything transactions can be read from, one record at a time. A record
+/// that can't be read yields an error without ending the source.
+pub trait RecordSource {
+    fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>>;
+
+    /// The line of the input the last record read starts at, for sources
+    /// that know it.
+    fn line(&self) -> Option<u64> {
+        None
+    }
+
+    /// The fields of the last record read, unless it was unreadable, for
+    /// sources that keep them.
+    fn record(&self) -> Option<&csv::StringRecord> {
+        None
+    }
+
+    /// The names of the fields of `record`.
+    fn headers(&self) -> Option<&csv::StringRecord> {
+        None
+    }
+}
+
+/// A record that was read but doesn't make a transaction, for sources
+/// that tell why: it is rejected as `kind` rather than as `unreadable`.
+#[derive(Debug)]
+pub struct InvalidRecord {
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Display for InvalidRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for InvalidRecord {}
+
+/// Transactions from CSV with a `type,client,tx,amount` header.
+pub struct CsvSource<R> {
+    records: csv::DeserializeRecordsIntoIter<R, Tx>,
+}
+
+impl<R: io::Read> CsvSource<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            records: csv::Reader::from_reader(input).into_deserialize(),
+        }
+    }
+}
+
+impl<R: io::Read> RecordSource for CsvSource<R> {
+    fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>> {
+        self.records.next().map(|tx| tx.map_err(Box::from))
+    }
+}
+
+/// The fields of transactions in newline-delimited JSON, in the order of
+/// the records made by `NdjsonRecords`; the same as the CSV columns.
+pub const NDJSON_FIELDS: [&str; 6] =
+    ["type", "client", "tx", "amount", "amount_minor", "to"];
+
+/// Records from newline-delimited JSON, one flat object per line like
+/// `{"type":"deposit","client":1,"tx":2,"amount":1.5}`, with the
+/// `NDJSON_FIELDS` as columns so that they deserialize like CSV rows.
+/// Missing and `null` fields are empty, numbers may also be quoted, other
+/// keys are ignored. Blank lines are skipped, but counted in the line
+/// numbers set as the records' positions.
+pub struct NdjsonRecords<R> {
+    lines: io::Lines<R>,
+    // of the last line read, 1-based
+    line: u64,
+}
+
+impl<R: BufRead> NdjsonRecords<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+            line: 0,
+        }
+    }
+}
+
+/// A line of newline-delimited JSON that isn't a flat object.
+#[derive(Debug, PartialEq)]
+pub struct NdjsonLineError {
+    // 1-based, blank lines included
+    pub line: u64,
+    pub err: ParseJsonError,
+}
+
+impl Display for NdjsonLineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.err)
+    }
+}
+
+impl Error for NdjsonLineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonRecords<R> {
+    type Item = Result<csv::StringRecord, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(match ndjson_record(&line) {
+                Ok(mut record) => {
+                    let mut pos = csv::Position::new();
+                    pos.set_line(self.line);
+                    record.set_position(Some(pos));
+                    Ok(record)
+                }
+                Err(err) => Err(Box::from(NdjsonLineError {
+                    line: self.line,
+                    err,
+                })),
+            });
+        }
+    }
+}
+
+fn ndjson_record(line: &str) -> Result<csv::StringRecord, ParseJsonError> {
+    let mut fields = [""; NDJSON_FIELDS.len()];
+    let object = parse_flat_object(line)?;
+    for (key, value) in &object {
+        if let Some(i) = NDJSON_FIELDS.iter().position(|field| field == key) {
+            fields[i] = value.as_deref().unwrap_or("");
+        }
+    }
+    Ok(csv::StringRecord::from(&fields[..]))
+}
+
+/// Transactions from newline-delimited JSON, see `NdjsonRecords`.
+pub struct NdjsonSource<R> {
+    records: NdjsonRecords<R>,
+    headers: csv::StringRecord,
+}
+
+impl<R: BufRead> NdjsonSource<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            records: NdjsonRecords::new(input),
+            headers: csv::StringRecord::from(&NDJSON_FIELDS[..]),
+        }
+    }
+}
+
+impl<R: BufRead> RecordSource for NdjsonSource<R> {
+    fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>> {
+        let record = self.records.next()?;
+        Some(
+            record.and_then(|record| {
+                Ok(record.deserialize(Some(&self.headers))?)
+            }),
+        )
+    }
+
+    fn line(&self) -> Option<u64> {
+        Some(self.records.line).filter(|line| *line > 0)
+    }
+}
+
+/// A record `process_source_reporting` rejected, in a form fit for
+/// machine-readable error reports.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rejection {
+    // 1-based position of the record in the source
+    pub record: usize,
+    // where the record starts, if the source knows
+    pub line: Option<u64>,
+    // `None` if the record couldn't be read
+    pub tx_id: Option<TxID>,
+    // `TxProcessingError::kind`, `InvalidRecord::kind`, or `unreadable`
+    pub kind: &'static str,
+    pub message: String,
+}
+
+/// Processes every transaction of `source`, returning how many records
+/// were rejected, either unreadable or failing to process.
+pub fn process_source(
+    tx_proc: &mut TxProcessor,
+    source: &mut impl RecordSource,
+) -> usize {
+    process_source_reporting(tx_proc, source, |rejection| {
+        eprintln!("rejected record: {}", rejection.message)
+    })
+}
+
+/// Like `process_source`, calling `on_rejected` with every rejected record
+/// instead of logging it.
+pub fn process_source_reporting(
+    tx_proc: &mut TxProcessor,
+    source: &mut impl RecordSource,
+    mut on_rejected: impl FnMut(Rejection),
+) -> usize {
+    let rejected = process_source_with(
+        tx_proc,
+        source,
+        |_, _| true,
+        |_, _, outcome| {
+            if let Outcome::Rejected(_, rejection) = outcome {
+                on_rejected(rejection)
+            }
+            ControlFlow::<Infallible>::Continue(())
+        },
+    );
+    rejected.unwrap_or_else(|never| match never {})
+}
+
+/// What became of a record of `process_source_with`.
+#[derive(Debug)]
+pub enum Outcome<'a> {
+    Applied(&'a Tx),
+    // passed over by `on_before`
+    Skipped(&'a Tx),
+    // with its transaction, unless the record didn't make one
+    Rejected(Option<&'a Tx>, Rejection),
+}
+
+/// Like `process_source_reporting`, with hooks around every record:
+/// `on_before` gets the position and the transaction of every record that
+/// makes one and skips it by returning `false`, and `on_after` gets what
+/// became of every record, along with the processor and the source to
+/// look at their state. Processing stops early with the value of the first
+/// `ControlFlow::Break` from `on_after`; otherwise the number of rejected
+/// records is returned.
+pub fn process_source_with<S: RecordSource, B>(
+    tx_proc: &mut TxProcessor,
+    source: &mut S,
+    mut on_before: impl FnMut(usize, &Tx) -> bool,
+    mut on_after: impl FnMut(&TxProcessor, &S, Outcome<'_>) -> ControlFlow<B>,
+) -> Result<usize, B> {
+    let mut rejected = 0;
+    let mut record = 0;
+    while let Some(tx) = source.next_tx() {
+        record += 1;
+        let tx = tx.map_err(|err| {
+            let kind = match err.downcast_ref::<InvalidRecord>() {
+                Some(invalid) => invalid.kind,
+                None => "unreadable",
+            };
+            Rejection {
+                record,
+                line: source.line(),
+                tx_id: None,
+                kind,
+                message: err.to_string(),
+            }
+        });
+        let outcome = match tx {
+            Ok(ref tx) if !on_before(record, tx) => Outcome::Skipped(tx),
+            Ok(ref tx) => match tx_proc.process(tx) {
+                Ok(()) => Outcome::Applied(tx),
+                Err(err) => Outcome::Rejected(
+                    Some(tx),
+                    Rejection {
+                        record,
+                        line: source.line(),
+                        tx_id: Some(tx.tx_id()),
+                        kind: err.kind(),
+                        message: err.to_string(),
+                    },
+                ),
+            },
+            Err(rejection) => Outcome::Rejected(None, rejection),
+        };
+        if let Outcome::Rejected(..) = outcome {
+            rejected += 1;
+        }
+        if let ControlFlow::Break(b) = on_after(tx_proc, source, outcome) {
+            return Err(b);
+        }
+    }
+    Ok(rejected)
+}
+
+impl TxProcessor {
+    /// A processor with the transactions CSV from `reader` applied, ready
+    /// for queries like `client_summary`, along with the records it
+    /// rejected.
+    pub fn from_reader<R: io::Read>(reader: R) -> (Self, Vec<Rejection>) {
+        let mut tx_proc = Self::new();
+        let mut rejections = Vec::new();
+        let mut source = CsvSource::new(reader);
+        process_source_reporting(&mut tx_proc, &mut source, |rejection| {
+            rejections.push(rejection)
+        });
+        (tx_proc, rejections)
+    }
+
+    /// `from_reader` for the CSV file at `path`.
+    pub fn from_csv_path(
+        path: impl AsRef<Path>,
+    ) -> io::Result<(Self, Vec<Rejection>)> {
+        let file = File::open(path)?;
+        Ok(Self::from_reader(io::BufReader::new(file)))
+    }
+}
+
+/// Why `process_csv` gave up. Rows that can't be read or processed are
+/// only logged.
+#[derive(Debug)]
+pub enum ProcessError {
+    // the input as a whole, e.g. its header
+    Read(csv::Error),
+    Write(csv::Error),
+}
+
+impl Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Read(ref err) => write!(f, "reading input: {}", err),
+            Self::Write(ref err) => write!(f, "writing summaries: {}", err),
+        }
+    }
+}
+
+impl Error for ProcessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Self::Read(ref err) | Self::Write(ref err) => Some(err),
+        }
+    }
+}
+
+/// Processes transactions CSV from `reader` and writes the client
+/// summaries to `writer` as CSV, like the `payments` binary does without
+/// options.
+pub fn process_csv<R: io::Read, W: io::Write>(
+    reader: R,
+    writer: W,
+) -> Result<(), ProcessError> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    rdr.headers().map_err(ProcessError::Read)?;
+    let mut source = CsvSource {
+        records: rdr.into_deserialize(),
+    };
+    let mut tx_proc = TxProcessor::new();
+    process_source(&mut tx_proc, &mut source);
+    write_logged_summaries(writer, &tx_proc)
+}
+
+/// Like `process_csv`, but reads newline-delimited JSON as described at
+/// `NdjsonRecords`. Lines that can't be parsed are logged and skipped, so
+/// only writing can fail.
+pub fn process_ndjson<R: io::Read, W: io::Write>(
+    reader: R,
+    writer: W,
+) -> Result<(), ProcessError> {
+    let mut source = NdjsonSource::new(io::BufReader::new(reader));
+    let mut tx_proc = TxProcessor::new();
+    process_source(&mut tx_proc, &mut source);
+    write_logged_summaries(writer, &tx_proc)
+}
+
+// a client whose balances can't be summarized is logged and left out, like
+// a rejected record, so that the other clients are still written
+fn write_logged_summaries<W: io::Write>(
+    writer: W,
+    tx_proc: &TxProcessor,
+) -> Result<(), ProcessError> {
+    let summaries = tx_proc.checked_client_summaries().filter_map(|summary| {
+        summary
+            .map_err(|err| eprintln!("skipping {}", err))
+            .ok()
+            .map(Ok)
+    });
+    write_summaries(writer, summaries, Columns::default())
+        .map_err(ProcessError::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use std::io::BufRead;
+
+    // `K CLIENT TX........ AMOUNT`, with columns at fixed offsets
+    struct FixedWidth<R> {
+        lines: io::Lines<R>,
+    }
+
+    impl<R: BufRead> RecordSource for FixedWidth<R> {
+        fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>> {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            Some(parse_fixed_width(&line))
+        }
+    }
+
+    fn parse_fixed_width(line: &str) -> Result<Tx, Box<dyn Error>> {
+        let field = |from: usize, to: usize| {
+            line.get(from..to.min(line.len()))
+                .map(str::trim)
+                .ok_or("short line")
+        };
+        let client_id = field(2, 7)?.parse::<u16>()?.into();
+        let tx_id = field(8, 18)?.parse::<u64>()?.into();
+        let amount = || -> Result<Amount, Box<dyn Error>> {
+            Ok(field(19, line.len())?.parse()?)
+        };
+        Ok(match field(0, 1)? {
+            "D" => Tx::Deposit {
+                client_id,
+                tx_id,
+                amount: amount()?,
+            },
+            "W" => Tx::Withdrawal {
+                client_id,
+                tx_id,
+                amount: amount()?,
+            },
+            "X" => Tx::Dispute { client_id, tx_id },
+            kind => return Err(format!("unknown kind {}", kind).into()),
+        })
+    }
+
+    #[test]
+    fn test_fixed_width_source() {
+        let input = "\
+D     1          1 10.5
+D     1          2 0.5
+Q     1          3
+X     1          1
+W     2          4 1
+";
+        let mut source = FixedWidth {
+            lines: input.as_bytes().lines(),
+        };
+        let mut tx_proc = TxProcessor::new();
+        assert_eq!(process_source(&mut tx_proc, &mut source), 2);
+        let summary = tx_proc.client_summary(1.into()).unwrap();
+        assert_eq!(summary.available, "0.5".parse::<Amount>().unwrap());
+        assert_eq!(summary.held, "10.5".parse().unwrap());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let input = "type,client,tx,amount
+deposit,1,1,2
+deposit,2,2,5
+withdrawal,1,3,3
+withdrawal,1,4,0.5
+";
+        let (tx_proc, rejections) = TxProcessor::from_reader(input.as_bytes());
+        let summary = tx_proc.client_summary(1.into()).unwrap();
+        assert_eq!(summary.available, "1.5".parse::<Amount>().unwrap());
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].record, 3);
+        assert_eq!(rejections[0].kind, "insufficient_funds");
+
+        let path = std::env::temp_dir()
+            .join(format!("payments-from-path-{}.csv", std::process::id()));
+        std::fs::write(&path, input).unwrap();
+        let (from_path, _) = TxProcessor::from_csv_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(from_path.client_summaries().eq(tx_proc.client_summaries()));
+        assert!(TxProcessor::from_csv_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_csv_source() {
+        let input = "type,client,tx,amount
+deposit,1,1,2
+deposit,1,2,x
+withdrawal,1,3,0.5
+";
+        let mut tx_proc = TxProcessor::new();
+        let rejected =
+            process_source(&mut tx_proc, &mut CsvSource::new(input.as_bytes()));
+        assert_eq!(rejected, 1);
+        let summary = tx_proc.client_summary(1.into()).unwrap();
+        assert_eq!(summary.available, "1.5".parse::<Amount>().unwrap());
+    }
+
+    #[test]
+    fn test_process_source_reporting() {
+        let input = "type,client,tx,amount
+deposit,1,1,2
+deposit,1,2,x
+withdrawal,1,3,5
+";
+        let mut rejections = Vec::new();
+        let rejected = process_source_reporting(
+            &mut TxProcessor::new(),
+            &mut CsvSource::new(input.as_bytes()),
+            |rejection| rejections.push(rejection),
+        );
+        assert_eq!(rejected, 2);
+        assert_eq!(rejections[0].record, 2);
+        assert_eq!(
+            (rejections[0].tx_id, rejections[0].kind),
+            (None, "unreadable")
+        );
+        assert_eq!(
+            rejections[1],
+            Rejection {
+                record: 3,
+                line: None,
+                tx_id: Some(3.into()),
+                kind: "insufficient_funds",
+                message: "insufficient funds".to_string(),
+            }
+        );
+    }
+
+    // a JSON source whose third line is rejected as `odd` instead
+    struct NoThirdLine<R>(NdjsonSource<R>);
+
+    impl<R: BufRead> RecordSource for NoThirdLine<R> {
+        fn next_tx(&mut self) -> Option<Result<Tx, Box<dyn Error>>> {
+            let tx = self.0.next_tx()?;
+            if self.line() == Some(3) {
+                let message = "third line".to_string();
+                return Some(Err(InvalidRecord {
+                    kind: "odd",
+                    message,
+                }
+                .into()));
+            }
+            Some(tx)
+        }
+
+        fn line(&self) -> Option<u64> {
+            self.0.line()
+        }
+    }
+
+    #[test]
+    fn test_process_source_with() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":2}
+
+{"type":"deposit","client":1,"tx":2,"amount":1}
+{"type":"deposit","client":1,"tx":3,"amount":1}
+{"type":"withdrawal","client":1,"tx":4,"amount":5}
+{"type":"deposit","client":1,"tx":5,"amount":1}
+{"type":"deposit","client":1,"tx":6,"amount":1}
+"#;
+        let mut tx_proc = TxProcessor::new();
+        let mut source = NoThirdLine(NdjsonSource::new(input.as_bytes()));
+        let mut outcomes = Vec::new();
+        let stopped = process_source_with(
+            &mut tx_proc,
+            &mut source,
+            |_, tx| tx.tx_id() != 3.into(),
+            |tx_proc, source, outcome| {
+                let total = tx_proc.client_summary(1.into()).unwrap().total;
+                outcomes.push(match outcome {
+                    Outcome::Applied(tx) => format!("applied {}", tx.tx_id()),
+                    Outcome::Skipped(tx) => format!("skipped {}", tx.tx_id()),
+                    Outcome::Rejected(_, rejection) => format!(
+                        "{} {} at {:?}",
+                        rejection.record,
+                        rejection.kind,
+                        source.line()
+                    ),
+                });
+                match total.to_string().as_str() {
+                    "3" => ControlFlow::Break(total),
+                    _ => ControlFlow::Continue(()),
+                }
+            },
+        );
+        assert_eq!(stopped, Err("3".parse().unwrap()));
+        assert_eq!(
+            outcomes,
+            [
+                "applied 1",
+                "2 odd at Some(3)",
+                "skipped 3",
+                "4 insufficient_funds at Some(5)",
+                "applied 5",
+            ]
+        );
+
+        let rejected = process_source_with(
+            &mut TxProcessor::new(),
+            &mut NdjsonSource::new(input.as_bytes()),
+            |_, _| true,
+            |_, _, _| ControlFlow::<()>::Continue(()),
+        );
+        assert_eq!(rejected, Ok(1));
+    }
+
+    #[test]
+    fn test_process_csv() {
+        let input = "type,client,tx,amount
+deposit,2,1,2
+deposit,1,2,x
+withdrawal,2,3,0.5
+deposit,1,4,1
+";
+        let mut output = Vec::new();
+        process_csv(input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,1,0,1,false
+2,1.5,0,1.5,false
+"
+        );
+
+        let err = process_csv(&b"type,\xff\n"[..], io::sink()).unwrap_err();
+        assert!(matches!(err, ProcessError::Read(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_ndjson_source() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":2.5}
+{"type":"deposit","client":1,"tx":2,"amount":"1.25","note":"x"}
+
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+{"type":"dispute","client":1,"tx":2,"amount":""}
+{"type":"deposit","client":1,"tx":3,"amount":1.23456}
+{"type":"deposit","client":1,"tx":4}
+{"type":"deposit","client":1,"tx":5,"amount":{"value":1}}
+not json
+{"type":"withdrawal","client":2,"tx":6,"amount_minor":10000}
+{"type":"transfer","client":1,"tx":7,"amount":1,"to":3}
+"#;
+        let mut tx_proc = TxProcessor::new();
+        let mut source = NdjsonSource::new(input.as_bytes());
+        // too precise, missing, nested and not JSON at all
+        assert_eq!(process_source(&mut tx_proc, &mut source), 5);
+        let summary = tx_proc.client_summary(1.into()).unwrap();
+        assert_eq!(summary.available, "1.5".parse::<Amount>().unwrap());
+        assert_eq!(summary.held, "1.25".parse().unwrap());
+        assert!(tx_proc.client_summary(2.into()).is_some());
+        let summary = tx_proc.client_summary(3.into()).unwrap();
+        assert_eq!(summary.available, "1".parse::<Amount>().unwrap());
+    }
+
+    #[test]
+    fn test_ndjson_lines() {
+        let input = "{\"tx\":1}\n\n  \n{\"tx\":\n{\"tx\":2}\n";
+        let lines: Vec<Result<u64, u64>> = NdjsonRecords::new(input.as_bytes())
+            .map(|record| match record {
+                Ok(record) => Ok(record.position().unwrap().line()),
+                Err(err) => {
+                    Err(err.downcast::<NdjsonLineError>().unwrap().line)
+                }
+            })
+            .collect();
+        assert_eq!(lines, [Ok(1), Err(4), Ok(5)]);
+    }
+
+    #[test]
+    fn test_process_ndjson() {
+        let input = r#"{"type":"deposit","client":2,"tx":1,"amount":"2"}
+{"type":"deposit","client":1,"tx":2,"amount":"x"}
+{"type":"withdrawal","client":2,"tx":3,"amount":0.5}
+{"type":"deposit","client":1,"tx":4,"amount":1}
+"#;
+        let mut output = Vec::new();
+        process_ndjson(input.as_bytes(), &mut output).unwrap();
+        let mut csv_output = Vec::new();
+        let csv_input = "type,client,tx,amount
+deposit,2,1,2
+deposit,1,2,x
+withdrawal,2,3,0.5
+deposit,1,4,1
+";
+        process_csv(csv_input.as_bytes(), &mut csv_output).unwrap();
+        assert_eq!(output, csv_output);
+    }
+}
@@ -1,14 +1,52 @@
 use serde::de;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::num::TryFromIntError;
 
 use crate::amount::*;
 
-#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Copy, Clone)]
-pub struct TxID(u32);
-#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Copy, Clone)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+)]
+pub struct TxID(u64);
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Copy,
+    Clone,
+)]
 pub struct ClientID(u16);
 
-#[derive(Debug, Copy, Clone)]
+impl Display for TxID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for ClientID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum Tx {
     Deposit {
         client_id: ClientID,
@@ -32,42 +70,274 @@ pub enum Tx {
         client_id: ClientID,
         tx_id: TxID,
     },
+    /// Moves `amount` from the available funds of `from` to those of `to`,
+    /// either wholly or not at all.
+    Transfer {
+        from: ClientID,
+        to: ClientID,
+        tx_id: TxID,
+        amount: Amount,
+    },
 }
 
 // couldn't make tagged enum (de)serialization work with CSV,
 // so we'll read `TxRow`s and later convert them to `Tx`s
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
-enum TxKind {
+pub enum TxKind {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+}
+
+/// The codes some feeds use instead of names: 1 for deposits, 2 for
+/// withdrawals, 3 for disputes, 4 for resolves, 5 for chargebacks and 6
+/// for transfers.
+impl TryFrom<u8> for TxKind {
+    type Error = UnknownKindCode;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(TxKind::Deposit),
+            2 => Ok(TxKind::Withdrawal),
+            3 => Ok(TxKind::Dispute),
+            4 => Ok(TxKind::Resolve),
+            5 => Ok(TxKind::Chargeback),
+            6 => Ok(TxKind::Transfer),
+            _ => Err(UnknownKindCode(code)),
+        }
+    }
+}
+
+/// The inverse of `TryFrom<u8>`.
+impl From<TxKind> for u8 {
+    fn from(kind: TxKind) -> Self {
+        match kind {
+            TxKind::Deposit => 1,
+            TxKind::Withdrawal => 2,
+            TxKind::Dispute => 3,
+            TxKind::Resolve => 4,
+            TxKind::Chargeback => 5,
+            TxKind::Transfer => 6,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UnknownKindCode(pub u8);
+
+impl Display for UnknownKindCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown transaction kind code {}", self.0)
+    }
+}
+
+impl Error for UnknownKindCode {}
+
+impl Display for TxKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            TxKind::Deposit => "deposit",
+            TxKind::Withdrawal => "withdrawal",
+            TxKind::Dispute => "dispute",
+            TxKind::Resolve => "resolve",
+            TxKind::Chargeback => "chargeback",
+            TxKind::Transfer => "transfer",
+        };
+        write!(f, "{}", kind)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct TxRow {
+struct TxRow<'a> {
     #[serde(rename = "type")]
     kind: TxKind,
     #[serde(rename = "client")]
     client_id: ClientID,
     #[serde(rename = "tx")]
     tx_id: TxID,
-    amount: String,
+    // empty for disputes, resolves and chargebacks; borrowed from the
+    // record and only parsed where it's used, so it's ignored for those
+    // kinds and when `amount_minor` is given
+    #[serde(borrow)]
+    amount: Option<&'a str>,
+    // exact amount in units of 0.0001; takes precedence over `amount` when
+    // the column is present and not empty
+    #[serde(default)]
+    amount_minor: Option<u64>,
+    // the credited client of a transfer, whose `client` is the debited one;
+    // ignored for other kinds
+    #[serde(default)]
+    to: Option<ClientID>,
+}
+
+// why a row doesn't make a `Tx`
+#[derive(Debug)]
+enum RowError {
+    Amount(ParseAmountError),
+    MissingDestination,
+}
+
+impl From<ParseAmountError> for RowError {
+    fn from(err: ParseAmountError) -> Self {
+        RowError::Amount(err)
+    }
+}
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowError::Amount(err) => write!(f, "{}", err),
+            RowError::MissingDestination => {
+                write!(f, "transfer without a destination client")
+            }
+        }
+    }
+}
+
+/// Why `Tx::from_record` couldn't make a transaction of a record.
+#[derive(Debug)]
+pub enum ParseTxError {
+    // missing or malformed columns, e.g. an unknown kind
+    Row(csv::Error),
+    // of a deposit, withdrawal or transfer; with the record's position
+    Amount(ParseAmountError, Option<csv::Position>),
+    MissingDestination(Option<csv::Position>),
+}
+
+impl ParseTxError {
+    /// A stable name of the error for machine-readable reports: the
+    /// `ParseAmountError::kind` of amounts, `invalid_row` otherwise.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Amount(err, _) => err.kind(),
+            Self::Row(_) | Self::MissingDestination(_) => "invalid_row",
+        }
+    }
+}
+
+// the position of a record like CSV errors show it, if there is one
+struct At<'a>(&'a Option<csv::Position>);
+
+impl Display for At<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(pos) => write!(
+                f,
+                "record {} (line: {}, byte: {}): ",
+                pos.record(),
+                pos.line(),
+                pos.byte()
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for ParseTxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Row(err) => write!(f, "{}", err),
+            Self::Amount(err, pos) => write!(f, "{}{}", At(pos), err),
+            Self::MissingDestination(pos) => {
+                write!(f, "{}{}", At(pos), RowError::MissingDestination)
+            }
+        }
+    }
+}
+
+impl Error for ParseTxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Row(err) => Some(err),
+            Self::Amount(err, _) => Some(err),
+            Self::MissingDestination(_) => None,
+        }
+    }
 }
 
 impl Tx {
-    fn from_row(tx_row: TxRow) -> Result<Self, ParseAmountError> {
+    pub fn kind(&self) -> TxKind {
+        match self {
+            Tx::Deposit { .. } => TxKind::Deposit,
+            Tx::Withdrawal { .. } => TxKind::Withdrawal,
+            Tx::Dispute { .. } => TxKind::Dispute,
+            Tx::Resolve { .. } => TxKind::Resolve,
+            Tx::Chargeback { .. } => TxKind::Chargeback,
+            Tx::Transfer { .. } => TxKind::Transfer,
+        }
+    }
+
+    /// For transfers, the debited client.
+    pub fn client_id(&self) -> ClientID {
+        match *self {
+            Tx::Deposit { client_id, .. }
+            | Tx::Withdrawal { client_id, .. }
+            | Tx::Dispute { client_id, .. }
+            | Tx::Resolve { client_id, .. }
+            | Tx::Chargeback { client_id, .. } => client_id,
+            Tx::Transfer { from, .. } => from,
+        }
+    }
+
+    /// For disputes, resolves and chargebacks, the id of the deposit they
+    /// refer to.
+    pub fn tx_id(&self) -> TxID {
+        match *self {
+            Tx::Deposit { tx_id, .. }
+            | Tx::Withdrawal { tx_id, .. }
+            | Tx::Dispute { tx_id, .. }
+            | Tx::Resolve { tx_id, .. }
+            | Tx::Chargeback { tx_id, .. }
+            | Tx::Transfer { tx_id, .. } => tx_id,
+        }
+    }
+
+    /// Makes a transaction of a CSV record with the columns named by
+    /// `headers`, like deserializing the record does but with a structured
+    /// error. With `strict` amounts have to be in minimal form, see
+    /// `Amount::parse_strict`; like any amount, only where it's used.
+    pub fn from_record(
+        record: &csv::StringRecord,
+        headers: Option<&csv::StringRecord>,
+        strict: bool,
+    ) -> Result<Self, ParseTxError> {
+        let row: TxRow<'_> =
+            record.deserialize(headers).map_err(ParseTxError::Row)?;
+        let parse: AmountParser = if strict {
+            Amount::parse_strict
+        } else {
+            str::parse
+        };
+        Self::from_row(row, parse).map_err(|err| {
+            let position = record.position().cloned();
+            match err {
+                RowError::Amount(err) => ParseTxError::Amount(err, position),
+                RowError::MissingDestination => {
+                    ParseTxError::MissingDestination(position)
+                }
+            }
+        })
+    }
+
+    fn from_row(
+        tx_row: TxRow<'_>,
+        parse: AmountParser,
+    ) -> Result<Self, RowError> {
         match tx_row {
             TxRow {
                 kind: TxKind::Deposit,
                 client_id,
                 tx_id,
                 amount,
+                amount_minor,
+                ..
             } => {
-                let amount: Amount = amount.parse()?;
+                let amount = required_amount(amount, amount_minor, parse)?;
                 Ok(Tx::Deposit {
                     client_id,
                     tx_id,
@@ -79,8 +349,10 @@ impl Tx {
                 client_id,
                 tx_id,
                 amount,
+                amount_minor,
+                ..
             } => {
-                let amount: Amount = amount.parse()?;
+                let amount = required_amount(amount, amount_minor, parse)?;
                 Ok(Tx::Withdrawal {
                     client_id,
                     tx_id,
@@ -105,30 +377,320 @@ impl Tx {
                 tx_id,
                 ..
             } => Ok(Tx::Chargeback { client_id, tx_id }),
+            TxRow {
+                kind: TxKind::Transfer,
+                client_id,
+                tx_id,
+                amount,
+                amount_minor,
+                to,
+            } => {
+                let amount = required_amount(amount, amount_minor, parse)?;
+                Ok(Tx::Transfer {
+                    from: client_id,
+                    to: to.ok_or(RowError::MissingDestination)?,
+                    tx_id,
+                    amount,
+                })
+            }
         }
     }
 }
 
+// `str::parse` or `Amount::parse_strict`
+type AmountParser = fn(&str) -> Result<Amount, ParseAmountError>;
+
+fn required_amount(
+    amount: Option<&str>,
+    amount_minor: Option<u64>,
+    parse: AmountParser,
+) -> Result<Amount, ParseAmountError> {
+    match (amount_minor, amount) {
+        (Some(units), _) => Ok(Amount::from_minor_units(units)),
+        (None, Some(amount)) => parse(amount),
+        (None, None) => Err(ParseAmountError::Missing),
+    }
+}
+
 impl<'de> Deserialize<'de> for Tx {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let row: TxRow = Deserialize::deserialize(deserializer)?;
-        Self::from_row(row).map_err(de::Error::custom)
+        let row: TxRow<'de> = Deserialize::deserialize(deserializer)?;
+        Self::from_row(row, str::parse).map_err(de::Error::custom)
     }
 }
 
-#[cfg(test)]
+/// Ids convert from and to plain integers, e.g. for building transactions
+/// in code rather than reading them:
+///
+/// ```
+/// use payments::{ClientID, TxID};
+/// use std::convert::TryFrom;
+///
+/// let client_id = ClientID::from(7);
+/// let tx_id = TxID::from(42);
+/// assert_eq!((client_id.as_u16(), tx_id.as_u64()), (7, 42));
+/// assert_eq!(ClientID::try_from(7_u32), Ok(client_id));
+/// assert!(ClientID::try_from(70_000_u32).is_err());
+/// ```
 impl From<u16> for ClientID {
     fn from(x: u16) -> Self {
         Self(x)
     }
 }
 
-#[cfg(test)]
-impl From<u32> for TxID {
-    fn from(x: u32) -> Self {
+impl TryFrom<u32> for ClientID {
+    type Error = TryFromIntError;
+
+    fn try_from(x: u32) -> Result<Self, Self::Error> {
+        u16::try_from(x).map(Self)
+    }
+}
+
+impl TryFrom<u64> for ClientID {
+    type Error = TryFromIntError;
+
+    fn try_from(x: u64) -> Result<Self, Self::Error> {
+        u16::try_from(x).map(Self)
+    }
+}
+
+impl ClientID {
+    pub fn as_u16(self) -> u16 {
+        self.0
+    }
+}
+
+impl From<u64> for TxID {
+    fn from(x: u64) -> Self {
         Self(x)
     }
 }
+
+impl TryFrom<u128> for TxID {
+    type Error = TryFromIntError;
+
+    fn try_from(x: u128) -> Result<Self, Self::Error> {
+        u64::try_from(x).map(Self)
+    }
+}
+
+impl TxID {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wide_tx_id() {
+        let data = "type,client,tx,amount\ndispute,1,4294967296,\n";
+        let txs: Vec<Tx> = csv::Reader::from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let tx_id = TxID(u64::from(u32::MAX) + 1);
+        assert_eq!(
+            txs,
+            vec![Tx::Dispute {
+                client_id: ClientID(1),
+                tx_id
+            }]
+        );
+        assert_eq!(tx_id.to_string(), "4294967296");
+    }
+
+    #[test]
+    fn test_multi_line_amount() {
+        let data = "type,client,tx,amount\ndeposit,1,1,\"1.5\n\"\n";
+        let err = csv::Reader::from_reader(data.as_bytes())
+            .deserialize::<Tx>()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid character '\\n'"));
+    }
+
+    #[test]
+    fn test_optional_amount() {
+        let parse = |data: &str| {
+            csv::Reader::from_reader(data.as_bytes())
+                .deserialize::<Tx>()
+                .next()
+                .unwrap()
+        };
+        let (client_id, tx_id) = (ClientID(1), TxID(2));
+        assert_eq!(
+            parse("type,client,tx,amount\ndeposit,1,2,1.5\n").unwrap(),
+            Tx::Deposit {
+                client_id,
+                tx_id,
+                amount: Amount::from_minor_units(15000)
+            }
+        );
+        assert_eq!(
+            parse("type,client,tx,amount\nresolve,1,2,\n").unwrap(),
+            Tx::Resolve { client_id, tx_id }
+        );
+        // ignored where it isn't needed
+        for amount in ["1.5", "1.23456", "abc"] {
+            let data =
+                format!("type,client,tx,amount\ndispute,1,2,{}\n", amount);
+            assert_eq!(parse(&data).unwrap(), Tx::Dispute { client_id, tx_id });
+        }
+
+        for (data, message) in &[
+            ("deposit,1,2,", "missing amount"),
+            ("withdrawal,1,2,1.23456", "unsupported precision of >4"),
+            ("deposit,1,2,1844674407370956", "number is too large"),
+        ] {
+            let data = format!("type,client,tx,amount\n{}\n", data);
+            let err = parse(&data).unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_from_record() {
+        let mut rdr = csv::Reader::from_reader(
+            "type,client,tx,amount,amount_minor
+deposit,1,2,1.50,
+deposit,1,2,1.50,20000
+dispute,1,2,1.50,
+refund,1,2,1,
+"
+            .as_bytes(),
+        );
+        let headers = rdr.headers().unwrap().clone();
+        let records: Vec<csv::StringRecord> =
+            rdr.records().map(Result::unwrap).collect();
+        let parse = |i: usize, strict| {
+            Tx::from_record(&records[i], Some(&headers), strict)
+        };
+        let (client_id, tx_id) = (ClientID(1), TxID(2));
+        let amount = Amount::from_minor_units(15000);
+        let deposit = Tx::Deposit {
+            client_id,
+            tx_id,
+            amount,
+        };
+        assert_eq!(parse(0, false).unwrap(), deposit);
+        let err = parse(0, true).unwrap_err();
+        assert_eq!(err.kind(), "not_minimal");
+        assert!(err.to_string().starts_with("record 1 (line: 2, byte: "));
+
+        // the amount isn't used by these
+        assert!(parse(1, true).is_ok());
+        assert_eq!(parse(2, true).unwrap(), Tx::Dispute { client_id, tx_id });
+        assert_eq!(parse(3, true).unwrap_err().kind(), "invalid_row");
+    }
+
+    #[test]
+    fn test_transfer_row() {
+        let parse = |data: &str| {
+            csv::Reader::from_reader(data.as_bytes())
+                .deserialize::<Tx>()
+                .next()
+                .unwrap()
+        };
+        assert_eq!(
+            parse("type,client,tx,amount,to\ntransfer,1,2,1.5,3\n").unwrap(),
+            Tx::Transfer {
+                from: ClientID(1),
+                to: ClientID(3),
+                tx_id: TxID(2),
+                amount: Amount::from_minor_units(15000)
+            }
+        );
+        // other kinds leave the column empty
+        assert_eq!(
+            parse("type,client,tx,amount,to\ndispute,1,2,,\n").unwrap(),
+            Tx::Dispute {
+                client_id: ClientID(1),
+                tx_id: TxID(2)
+            }
+        );
+        for data in &[
+            "type,client,tx,amount\ntransfer,1,2,1.5\n",
+            "type,client,tx,amount,to\ntransfer,1,2,1.5,\n",
+        ] {
+            let err = parse(data).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("transfer without a destination client"),
+                "{}",
+                err
+            );
+        }
+        let err = parse("type,client,tx,amount,to\ntransfer,1,2,,3\n");
+        assert!(err.unwrap_err().to_string().contains("missing amount"));
+    }
+
+    #[test]
+    fn test_kind_codes() {
+        let kinds = [
+            TxKind::Deposit,
+            TxKind::Withdrawal,
+            TxKind::Dispute,
+            TxKind::Resolve,
+            TxKind::Chargeback,
+            TxKind::Transfer,
+        ];
+        for (code, kind) in (1..).zip(&kinds) {
+            assert_eq!(TxKind::try_from(code), Ok(*kind));
+            assert_eq!(u8::from(*kind), code);
+        }
+        for code in &[0, 7, 255] {
+            assert_eq!(TxKind::try_from(*code), Err(UnknownKindCode(*code)));
+        }
+        assert_eq!(
+            UnknownKindCode(9).to_string(),
+            "unknown transaction kind code 9"
+        );
+    }
+
+    #[test]
+    fn test_id_conversions() {
+        assert_eq!(ClientID::try_from(u32::from(u16::MAX)), Ok(ClientID(!0)));
+        assert!(ClientID::try_from(u32::from(u16::MAX) + 1).is_err());
+        assert!(ClientID::try_from(u64::MAX).is_err());
+        assert_eq!(TxID::try_from(u128::from(u64::MAX)), Ok(TxID(!0)));
+        assert!(TxID::try_from(u128::from(u64::MAX) + 1).is_err());
+        assert_eq!(ClientID::from(3).as_u16(), 3);
+        assert_eq!(TxID::from(5).as_u64(), 5);
+    }
+
+    #[test]
+    fn test_ids() {
+        let (client_id, tx_id) = (ClientID(7), TxID(42));
+        let amount = Amount::new();
+        for tx in &[
+            Tx::Deposit {
+                client_id,
+                tx_id,
+                amount,
+            },
+            Tx::Withdrawal {
+                client_id,
+                tx_id,
+                amount,
+            },
+            Tx::Dispute { client_id, tx_id },
+            Tx::Resolve { client_id, tx_id },
+            Tx::Chargeback { client_id, tx_id },
+            Tx::Transfer {
+                from: client_id,
+                to: ClientID(8),
+                tx_id,
+                amount,
+            },
+        ] {
+            assert_eq!((tx.client_id(), tx.tx_id()), (client_id, tx_id));
+        }
+    }
+}